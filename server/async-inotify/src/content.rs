@@ -0,0 +1,113 @@
+use std::{
+    fs::{File, Metadata},
+    io::Read,
+    path::Path,
+    time::SystemTime,
+};
+
+use sha2::{Digest as _, Sha256};
+
+use crate::futures::Digest;
+
+/// Last seen contents of a file, used to filter out writes which did not change anything
+///
+/// Starts out as the digest of an empty file.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ContentState {
+    digest: Digest,
+}
+
+impl Default for ContentState {
+    fn default() -> Self {
+        Self {
+            digest: digest(Sha256::new()),
+        }
+    }
+}
+
+impl ContentState {
+    const CHUNK: usize = 8 * 1024;
+
+    /// Larger files are not read, so that hashing one does not hold up every other watch. Their
+    /// digest is of their size and modification time instead.
+    pub(crate) const MAX_HASHED: u64 = 4 * 1024 * 1024;
+
+    /// Read the current state of the file at `path`, along with whether the file was opened to
+    /// do so, which any watch on it sees as an open, reads and a close
+    ///
+    /// Files which cannot be read are treated as empty, so that the first successful read after
+    /// they become available is reported as a change.
+    pub(crate) fn load(path: &Path) -> (Self, bool) {
+        let mut opened = false;
+
+        match Self::try_load(path, &mut opened) {
+            Ok(it) => (it, opened),
+            Err(e) => {
+                crate::warn!("Could not read {} for digest: {e}", path.display());
+
+                (Self::default(), opened)
+            }
+        }
+    }
+
+    fn try_load(path: &Path, opened: &mut bool) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        let mut hasher = Sha256::new();
+
+        if metadata.len() > Self::MAX_HASHED {
+            hash_metadata(&mut hasher, &metadata);
+        } else {
+            let mut file = File::open(path)?.take(Self::MAX_HASHED);
+            *opened = true;
+
+            let mut buf = [0u8; Self::CHUNK];
+
+            loop {
+                let read = file.read(&mut buf)?;
+                if read == 0 {
+                    break;
+                }
+
+                hasher.update(&buf[..read]);
+            }
+        }
+
+        Ok(Self {
+            digest: digest(hasher),
+        })
+    }
+
+    /// Hash the file at `path` again, returning the previous and new digest if the contents
+    /// differ, along with whether the file was opened as for [`load`]
+    ///
+    /// [`load`]: ContentState::load
+    pub(crate) fn update(&mut self, path: &Path) -> (Option<(Digest, Digest)>, bool) {
+        let previous = self.digest;
+        let (next, opened) = Self::load(path);
+
+        *self = next;
+
+        let changed = self.digest != previous;
+        (changed.then_some((previous, self.digest)), opened)
+    }
+}
+
+fn hash_metadata(hasher: &mut Sha256, metadata: &Metadata) {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|it| it.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+
+    hasher.update(metadata.len().to_be_bytes());
+    hasher.update(modified.as_nanos().to_be_bytes());
+}
+
+/// The first eight bytes of the hash, big endian
+fn digest(hasher: Sha256) -> Digest {
+    let hash = hasher.finalize();
+    let mut first = [0; 8];
+    first.copy_from_slice(&hash[..8]);
+
+    Digest(u64::from_be_bytes(first))
+}
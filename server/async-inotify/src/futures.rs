@@ -1,19 +1,92 @@
 use std::{
     fmt::{Display, Formatter},
     future::Future,
+    num::ParseIntError,
     pin::Pin,
+    str::FromStr,
+    sync::{Arc, Weak},
 };
 
 use nix::sys::inotify::AddWatchFlags;
-use tokio::sync::oneshot::Receiver as OnceRecv;
-use tokio_stream::{wrappers::ReceiverStream, Stream};
+use thiserror::Error;
+use tokio::sync::{
+    broadcast::{Receiver as BroadcastRecv, Sender as BroadcastSend},
+    oneshot::Receiver as OnceRecv,
+    Notify,
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, ReceiverStream},
+    Stream,
+};
+
+/// Digest of the contents of a file, as seen by a [`content_changed`] watch
+///
+/// The first eight bytes of the SHA-256 of the file, big endian. Files too large to be read in the
+/// watcher task are hashed by their size and modification time instead.
+///
+/// [`content_changed`]: crate::handle::WatchRequest::content_changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_impl::Serialize, serde_impl::Deserialize),
+    serde(crate = "serde_impl", into = "String", try_from = "String")
+)]
+pub struct Digest(pub u64);
 
+impl Display for Digest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for Digest {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        u64::from_str_radix(s, 16).map(Digest)
+    }
+}
+
+// Digests are written as hex strings, as not every consumer can represent a full u64
+impl From<Digest> for String {
+    fn from(it: Digest) -> Self {
+        it.to_string()
+    }
+}
+
+impl TryFrom<String> for Digest {
+    type Error = ParseIntError;
+
+    fn try_from(it: String) -> Result<Self, Self::Error> {
+        it.parse()
+    }
+}
+
+/// Serialized with an internal `kind` tag, as in `{"kind": "close", "writable": true}`
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_impl::Serialize, serde_impl::Deserialize),
+    serde(crate = "serde_impl", tag = "kind", rename_all = "snake_case")
+)]
 pub enum FileWatchEvent {
     Read,
     Write,
     Open,
-    Close { writable: bool },
+    Close {
+        writable: bool,
+    },
+
+    /// The contents of the file were replaced with different bytes
+    ///
+    /// Only produced by watches with [`content_changed`] set, in place of [`Write`]
+    ///
+    /// [`content_changed`]: crate::handle::WatchRequest::content_changed
+    /// [`Write`]: FileWatchEvent::Write
+    Changed {
+        previous: Digest,
+        current: Digest,
+    },
 }
 
 impl TryFrom<AddWatchFlags> for FileWatchEvent {
@@ -50,13 +123,21 @@ impl Display for FileWatchEvent {
                     "for writing"
                 }
             ),
+            Changed { previous, current } => write!(f, "changed ({previous} -> {current})"),
         }
     }
 }
 
+/// Serialized with the fields of the event inline, as in `{"inner_path": "a.txt", "kind": "write"}`
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_impl::Serialize, serde_impl::Deserialize),
+    serde(crate = "serde_impl")
+)]
 pub struct DirectoryWatchEvent {
     pub inner_path: Option<String>,
+    #[cfg_attr(feature = "serde", serde(flatten))]
     pub event: FileWatchEvent,
 }
 
@@ -70,12 +151,98 @@ impl Display for DirectoryWatchEvent {
     }
 }
 
+/// A shared subscription fell behind the watch, and this many events were dropped for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("Subscriber fell behind and missed {0} events")]
+pub struct Lagged(pub u64);
+
 /// Single Event File Watch
 pub struct FileWatchFuture(pub(crate) OnceRecv<DirectoryWatchEvent>);
 pub struct FileWatchStream(pub(crate) ReceiverStream<DirectoryWatchEvent>);
 pub struct DirectoryWatchFuture(pub(crate) OnceRecv<DirectoryWatchEvent>);
 pub struct DirectoryWatchStream(pub(crate) ReceiverStream<DirectoryWatchEvent>);
 
+/// Subscription to a file watch which may be shared between consumers
+///
+/// Cloning creates another subscription backed by the same kernel watch, which will only see
+/// events that happen after it was created. The kernel watch is removed once the last
+/// subscription is dropped.
+#[derive(Clone)]
+pub struct SharedFileWatch(pub(crate) Shared);
+
+/// Subscription to a directory watch which may be shared between consumers
+///
+/// Cloning creates another subscription backed by the same kernel watch, which will only see
+/// events that happen after it was created. The kernel watch is removed once the last
+/// subscription is dropped.
+#[derive(Clone)]
+pub struct SharedDirectoryWatch(pub(crate) Shared);
+
+pub(crate) struct Shared {
+    // Only the watcher task holds the sender strongly, so that subscriptions end when it does
+    sender: Weak<BroadcastSend<DirectoryWatchEvent>>,
+    stream: BroadcastStream<DirectoryWatchEvent>,
+    // Wakes the watcher task on drop, to remove any watches with no subscriptions left
+    released: Arc<Notify>,
+}
+
+impl Shared {
+    pub(crate) fn new(
+        sender: &Arc<BroadcastSend<DirectoryWatchEvent>>,
+        released: Arc<Notify>,
+    ) -> Self {
+        Self {
+            sender: Arc::downgrade(sender),
+            stream: BroadcastStream::new(sender.subscribe()),
+            released,
+        }
+    }
+
+    /// A subscription to a channel that has already closed
+    fn closed() -> BroadcastRecv<DirectoryWatchEvent> {
+        tokio::sync::broadcast::channel(1).1
+    }
+}
+
+impl Clone for Shared {
+    fn clone(&self) -> Self {
+        let receiver = match self.sender.upgrade() {
+            Some(sender) => sender.subscribe(),
+            // The watch has already ended, so hand out a subscription that is closed too
+            None => Self::closed(),
+        };
+
+        Self {
+            sender: self.sender.clone(),
+            stream: BroadcastStream::new(receiver),
+            released: self.released.clone(),
+        }
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // Other subscriptions may be dropped at the same time, so the watcher task is always
+        // woken and left to check which watches have no receivers left. Our receiver is let go
+        // of first, so that it is not counted.
+        self.stream = BroadcastStream::new(Self::closed());
+        self.released.notify_one();
+    }
+}
+
+impl Stream for Shared {
+    type Item = Result<DirectoryWatchEvent, Lagged>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx).map(|it| {
+            it.map(|event| event.map_err(|BroadcastStreamRecvError::Lagged(missed)| Lagged(missed)))
+        })
+    }
+}
+
 impl Future for FileWatchFuture {
     type Output = Option<FileWatchEvent>;
 
@@ -124,3 +291,27 @@ impl Stream for DirectoryWatchStream {
         Pin::new(&mut self.0).poll_next(cx)
     }
 }
+
+impl Stream for SharedFileWatch {
+    type Item = Result<FileWatchEvent, Lagged>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0)
+            .poll_next(cx)
+            .map(|it| it.map(|event| event.map(|event| event.event)))
+    }
+}
+
+impl Stream for SharedDirectoryWatch {
+    type Item = Result<DirectoryWatchEvent, Lagged>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0).poll_next(cx)
+    }
+}
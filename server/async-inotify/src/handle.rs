@@ -3,23 +3,45 @@ use std::{
     marker::PhantomData,
     ops::{Deref, DerefMut},
     path::PathBuf,
+    sync::{atomic::Ordering, Arc},
     time::Duration,
 };
 use thiserror::Error;
 use tokio::{
-    sync::{mpsc::Sender as MpscSend, oneshot::Sender as OnceSend},
+    sync::{mpsc::Sender as MpscSend, oneshot::Sender as OnceSend, Notify},
     task::JoinHandle,
 };
 use tokio_stream::wrappers::ReceiverStream;
 
 use crate::{
-    futures::{DirectoryWatchFuture, DirectoryWatchStream, FileWatchFuture, FileWatchStream},
-    task::WatchRequestInner,
+    futures::{
+        DirectoryWatchFuture, DirectoryWatchStream, FileWatchFuture, FileWatchStream, Shared,
+        SharedDirectoryWatch, SharedFileWatch,
+    },
+    task::{Counters, WatchRequestInner},
 };
 
+/// `IN_EXCL_UNLINK`, which is not named by this version of nix
+// SAFETY: add_watch passes the bits straight through to the kernel, which understands this one
+pub(crate) const IN_EXCL_UNLINK: AddWatchFlags =
+    unsafe { AddWatchFlags::from_bits_unchecked(nix::libc::IN_EXCL_UNLINK) };
+
 #[derive(Debug, Clone)]
 pub struct Handle {
     pub(crate) request_tx: MpscSend<WatchRequestInner>,
+    pub(crate) counters: Arc<Counters>,
+    pub(crate) released: Arc<Notify>,
+}
+
+/// What the watcher task is doing, as of the last request or batch of events it handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Kernel watch descriptors currently held
+    pub watches: usize,
+    /// Watches handed out which are still being served, several of which may share a descriptor
+    pub watchers: usize,
+    /// Events read from the kernel since the watcher task started
+    pub events: u64,
 }
 
 #[derive(Debug)]
@@ -82,12 +104,32 @@ pub enum RequestError {
 pub enum WatchError {
     #[error("The watcher task was shutdown while before the next event could be received")]
     WatcherShutdown,
+
+    #[error(transparent)]
+    Request(#[from] RequestError),
 }
 
 impl Handle {
+    /// Whether the watcher task is still running, and so can serve new watches
+    pub fn is_running(&self) -> bool {
+        !self.request_tx.is_closed()
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            watches: self.counters.watches.load(Ordering::Relaxed),
+            watchers: self.counters.watchers.load(Ordering::Relaxed),
+            events: self.counters.events.load(Ordering::Relaxed),
+        }
+    }
+
     /// Create a file watch builder
+    ///
+    /// A symbolic link whose target is missing can still be watched, as long as it is not
+    /// followed.
     pub fn file(&mut self, path: PathBuf) -> Result<WatchRequest<'_, FileEvents>, RequestError> {
-        if !path.exists() {
+        // Whether a link has to lead somewhere is only known once the watch is started
+        if path.symlink_metadata().is_err() {
             return Err(RequestError::DoesNotExist(path));
         }
         if path.is_dir() {
@@ -101,6 +143,8 @@ impl Handle {
             path,
             buffer,
             flags: AddWatchFlags::empty(),
+            options: AddWatchFlags::empty(),
+            content: false,
             _type: Default::default(),
         })
     }
@@ -124,6 +168,8 @@ impl Handle {
             path,
             buffer,
             flags: AddWatchFlags::empty(),
+            options: AddWatchFlags::IN_ONLYDIR,
+            content: false,
             _type: Default::default(),
         })
     }
@@ -135,6 +181,9 @@ mod sealed {
 
 pub trait WatchType: sealed::Sealed {
     const DEFAULT_BUFFER: usize;
+
+    #[doc(hidden)]
+    const DIR: bool;
 }
 
 pub enum FileEvents {}
@@ -145,10 +194,12 @@ impl sealed::Sealed for DirectoryEvents {}
 
 impl WatchType for FileEvents {
     const DEFAULT_BUFFER: usize = 16;
+    const DIR: bool = false;
 }
 
 impl WatchType for DirectoryEvents {
     const DEFAULT_BUFFER: usize = 32;
+    const DIR: bool = true;
 }
 
 pub struct WatchRequest<'handle, T: WatchType> {
@@ -156,6 +207,8 @@ pub struct WatchRequest<'handle, T: WatchType> {
     path: PathBuf,
     buffer: usize,
     flags: AddWatchFlags,
+    options: AddWatchFlags,
+    content: bool,
     _type: PhantomData<T>,
 }
 
@@ -190,28 +243,74 @@ impl<T: WatchType> WatchRequest<'_, T> {
         self
     }
 
+    /// Set weather a symbolic link should be followed, defaults to `true`
+    ///
+    /// When following, the link is resolved when the watch is started and its target is watched,
+    /// so a symlinked world folder reports changes to the real folder. Otherwise the link itself
+    /// is watched (`IN_DONT_FOLLOW`), and replacing the target is not noticed.
+    pub fn follow_symlinks(mut self, set: bool) -> Self {
+        self.options.set(AddWatchFlags::IN_DONT_FOLLOW, !set);
+        self
+    }
+
     // TODO(josiah) moves will require a more robust background task so that move events can be
     // coalesced correctly
-}
 
-impl<'handle> WatchRequest<'handle, FileEvents> {
-    /// Create a watch which will only return the next captured event, and then unsubscribe
-    ///
-    /// Ignores the value set by [`buffer`]
-    pub fn next(self) -> Result<FileWatchFuture, WatchError> {
-        let (sender, rx) = tokio::sync::oneshot::channel();
+    /// Send the request to start this watch to the watcher task, waiting for room when it is
+    /// behind on requests
+    async fn start(self, sender: crate::task::Sender, once: bool) -> Result<(), WatchError> {
+        let path = if self.options.contains(AddWatchFlags::IN_DONT_FOLLOW) {
+            self.path
+        } else {
+            match self.path.canonicalize() {
+                Ok(path) => path,
+                Err(_) => return Err(RequestError::DoesNotExist(self.path).into()),
+            }
+        };
 
-        let sender = crate::task::Sender::Once(sender);
+        let mut options = self.options;
+        options.set(AddWatchFlags::IN_ONESHOT, once);
 
         self.handle
             .request_tx
-            .try_send(WatchRequestInner::Start {
+            .send(WatchRequestInner::Start {
                 flags: self.flags,
-                path: self.path,
-                dir: false,
+                options,
+                path,
+                dir: T::DIR,
+                content: self.content,
                 sender,
             })
-            .map_err(|_| WatchError::WatcherShutdown)?;
+            .await
+            .map_err(|_| WatchError::WatcherShutdown)
+    }
+}
+
+impl<'handle> WatchRequest<'handle, FileEvents> {
+    /// Set weather writes should only be reported when they change the contents of the file
+    ///
+    /// The watch keeps a digest of the file, and hashes it again each time a writer closes the
+    /// file. Writes which leave the same bytes in place are dropped, and real changes are
+    /// reported as [`FileWatchEvent::Changed`] with the previous and new digest, instead of
+    /// [`FileWatchEvent::Write`]. Closes asked for with [`close`] are still reported, but the
+    /// opens, reads and closes of hashing the file are not.
+    ///
+    /// [`close`]: WatchRequest::close
+    /// [`FileWatchEvent::Changed`]: crate::futures::FileWatchEvent::Changed
+    /// [`FileWatchEvent::Write`]: crate::futures::FileWatchEvent::Write
+    pub fn content_changed(mut self, set: bool) -> Self {
+        self.content = set;
+        self
+    }
+
+    /// Create a watch which will only return the next captured event, and then unsubscribe
+    ///
+    /// Uses a one shot kernel watch (`IN_ONESHOT`) when nothing else is watching the same path.
+    /// Ignores the value set by [`buffer`]
+    pub async fn next(self) -> Result<FileWatchFuture, WatchError> {
+        let (sender, rx) = tokio::sync::oneshot::channel();
+
+        self.start(crate::task::Sender::Once(sender), true).await?;
 
         Ok(FileWatchFuture(rx))
     }
@@ -219,43 +318,60 @@ impl<'handle> WatchRequest<'handle, FileEvents> {
     /// Create a watch which will capture and return a stream of events until dropped.
     ///
     /// Will keep oldest events on buffer overflow set by [`buffer`]
-    pub fn watch(self) -> Result<FileWatchStream, WatchError> {
+    pub async fn watch(self) -> Result<FileWatchStream, WatchError> {
         let (sender, rx) = tokio::sync::mpsc::channel(self.buffer);
 
-        let sender = crate::task::Sender::Stream(sender);
-
-        self.handle
-            .request_tx
-            .try_send(WatchRequestInner::Start {
-                flags: self.flags,
-                path: self.path,
-                dir: false,
-                sender,
-            })
-            .map_err(|_| WatchError::WatcherShutdown)?;
+        self.start(crate::task::Sender::Stream(sender), false)
+            .await?;
 
         Ok(FileWatchStream(ReceiverStream::from(rx)))
     }
+
+    /// Create a watch which can be shared between several consumers, backed by a single kernel
+    /// watch. The watch is removed once the last subscription has been dropped.
+    ///
+    /// Each subscription buffers up to the amount set by [`buffer`], and is told how many events
+    /// it missed if it falls further behind than that.
+    pub async fn share(self) -> Result<SharedFileWatch, WatchError> {
+        let sender = Arc::new(tokio::sync::broadcast::channel(self.buffer.max(1)).0);
+        let shared = Shared::new(&sender, self.handle.released.clone());
+
+        self.start(crate::task::Sender::Broadcast(sender), false)
+            .await?;
+
+        Ok(SharedFileWatch(shared))
+    }
 }
 
 impl<'handle> WatchRequest<'handle, DirectoryEvents> {
+    /// Set weather the kernel should refuse to watch the path unless it is a directory
+    /// (`IN_ONLYDIR`), defaults to `true`
+    ///
+    /// This guards against the directory being replaced between this request being built and the
+    /// watch being started.
+    pub fn only_dir(mut self, set: bool) -> Self {
+        self.options.set(AddWatchFlags::IN_ONLYDIR, set);
+        self
+    }
+
+    /// Set weather events should stop being reported for children once they are unlinked from the
+    /// directory (`IN_EXCL_UNLINK`), even while they are still held open
+    ///
+    /// The kernel keeps one watch for each directory, so a request which disagrees on this with
+    /// another watch of the same directory is refused, and its stream ends straight away.
+    pub fn excl_unlink(mut self, set: bool) -> Self {
+        self.options.set(IN_EXCL_UNLINK, set);
+        self
+    }
+
     /// Create a watch which will only return the next captured event, and then unsubscribe
     ///
+    /// Uses a one shot kernel watch (`IN_ONESHOT`) when nothing else is watching the same path.
     /// Ignores the value set by [`buffer`]
-    pub fn next(self) -> Result<DirectoryWatchFuture, WatchError> {
+    pub async fn next(self) -> Result<DirectoryWatchFuture, WatchError> {
         let (sender, rx) = tokio::sync::oneshot::channel();
 
-        let sender = crate::task::Sender::Once(sender);
-
-        self.handle
-            .request_tx
-            .try_send(WatchRequestInner::Start {
-                flags: self.flags,
-                path: self.path,
-                dir: true,
-                sender,
-            })
-            .map_err(|_| WatchError::WatcherShutdown)?;
+        self.start(crate::task::Sender::Once(sender), true).await?;
 
         Ok(DirectoryWatchFuture(rx))
     }
@@ -263,21 +379,27 @@ impl<'handle> WatchRequest<'handle, DirectoryEvents> {
     /// Create a watch which will capture and return a stream of events until dropped.
     ///
     /// Will keep oldest events on buffer overflow set by [`buffer`]
-    pub fn watch(self) -> Result<DirectoryWatchStream, WatchError> {
+    pub async fn watch(self) -> Result<DirectoryWatchStream, WatchError> {
         let (sender, rx) = tokio::sync::mpsc::channel(self.buffer);
 
-        let sender = crate::task::Sender::Stream(sender);
-
-        self.handle
-            .request_tx
-            .try_send(WatchRequestInner::Start {
-                flags: self.flags,
-                path: self.path,
-                dir: true,
-                sender,
-            })
-            .map_err(|_| WatchError::WatcherShutdown)?;
+        self.start(crate::task::Sender::Stream(sender), false)
+            .await?;
 
         Ok(DirectoryWatchStream(ReceiverStream::from(rx)))
     }
+
+    /// Create a watch which can be shared between several consumers, backed by a single kernel
+    /// watch. The watch is removed once the last subscription has been dropped.
+    ///
+    /// Each subscription buffers up to the amount set by [`buffer`], and is told how many events
+    /// it missed if it falls further behind than that.
+    pub async fn share(self) -> Result<SharedDirectoryWatch, WatchError> {
+        let sender = Arc::new(tokio::sync::broadcast::channel(self.buffer.max(1)).0);
+        let shared = Shared::new(&sender, self.handle.released.clone());
+
+        self.start(crate::task::Sender::Broadcast(sender), false)
+            .await?;
+
+        Ok(SharedDirectoryWatch(shared))
+    }
 }
@@ -3,9 +3,14 @@ extern crate thiserror;
 extern crate tokio;
 extern crate tokio_stream;
 
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+
 use handle::{Handle, OwnedHandle};
 use task::InitError;
 
+mod content;
 pub mod futures;
 pub mod handle;
 mod task;
@@ -16,13 +21,21 @@ mod tracing;
 // as max watchers
 pub fn new() -> Result<OwnedHandle, InitError> {
     let (request_tx, request_rx) = tokio::sync::mpsc::channel(OwnedHandle::DEFAULT_REQUEST_BUFFER);
-    let inner = Handle { request_tx };
+    let counters = Arc::new(task::Counters::default());
+    let released = Arc::new(Notify::new());
+    let inner = Handle {
+        request_tx,
+        counters: counters.clone(),
+        released: released.clone(),
+    };
     let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
 
     let join = task::WatcherState::launch(Box::new(task::WatcherState::new(
         request_rx,
         shutdown_rx,
         None,
+        counters,
+        released,
     )?));
 
     Ok(OwnedHandle {
@@ -40,7 +53,10 @@ mod test {
     use tokio::{test, time::Timeout};
     use tokio_stream::StreamExt;
 
-    use crate::futures::FileWatchEvent;
+    use crate::{
+        futures::{FileWatchEvent, Lagged},
+        handle::{RequestError, WatchError},
+    };
 
     fn setup_testdir() -> TempDir {
         TempDir::new("testdir").unwrap()
@@ -63,6 +79,16 @@ mod test {
         }
 
         fn change(&mut self) {
+            self.write(self.1);
+            self.1 += 1;
+        }
+
+        /// Write the same contents as the last call to [`change`] again
+        fn rewrite(&mut self) {
+            self.write(self.1.saturating_sub(1));
+        }
+
+        fn write(&self, value: usize) {
             let mut file = std::fs::OpenOptions::new()
                 .read(true)
                 .write(true)
@@ -70,10 +96,9 @@ mod test {
                 .open(&self.0)
                 .unwrap();
 
-            write!(&mut file, "{}: {}", self.0.display(), self.1).unwrap();
+            write!(&mut file, "{}: {}", self.0.display(), value).unwrap();
             file.flush().unwrap();
             drop(file);
-            self.1 += 1;
         }
     }
 
@@ -84,7 +109,15 @@ mod test {
         let file_path = test_dir.path().join("test.txt");
         let mut file = TestFile::new(file_path.clone());
 
-        let fut = timeout(owner.file(file_path).unwrap().modify(true).next().unwrap());
+        let fut = timeout(
+            owner
+                .file(file_path)
+                .unwrap()
+                .modify(true)
+                .next()
+                .await
+                .unwrap(),
+        );
 
         wait().await;
 
@@ -111,7 +144,13 @@ mod test {
         let file_path = test_dir.path().join("test.txt");
         let file = TestFile::new(file_path.clone());
 
-        let mut stream = owner.file(file_path).unwrap().modify(true).watch().unwrap();
+        let mut stream = owner
+            .file(file_path)
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
 
         tokio::spawn(async move {
             let mut file = file;
@@ -135,6 +174,354 @@ mod test {
         assert_eq!(3, count, "Did not get the correct number of events");
     }
 
+    #[test]
+    async fn content_changed() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let file = TestFile::new(file_path.clone());
+
+        let mut stream = owner
+            .file(file_path)
+            .unwrap()
+            .content_changed(true)
+            .watch()
+            .await
+            .unwrap();
+
+        tokio::spawn(async move {
+            let mut file = file;
+
+            wait().await;
+            file.change();
+            wait().await;
+            file.rewrite();
+            wait().await;
+            file.change();
+
+            drop(file);
+        });
+
+        let mut events = Vec::new();
+        while let Ok(Some(item)) = timeout(stream.next()).await {
+            events.push(item);
+        }
+
+        match events[..] {
+            [FileWatchEvent::Changed {
+                previous: first_previous,
+                current: first_current,
+            }, FileWatchEvent::Changed {
+                previous: second_previous,
+                current: second_current,
+            }] => {
+                assert_ne!(first_previous, first_current);
+                assert_eq!(first_current, second_previous);
+                assert_ne!(second_previous, second_current);
+            }
+            _ => panic!("Expected exactly two content changes, got {events:#?}"),
+        }
+    }
+
+    #[test]
+    async fn content_changed_keeps_close() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let file = TestFile::new(file_path.clone());
+
+        let mut stream = owner
+            .file(file_path)
+            .unwrap()
+            .content_changed(true)
+            .open(true)
+            .close(true)
+            .watch()
+            .await
+            .unwrap();
+
+        tokio::spawn(async move {
+            let mut file = file;
+
+            wait().await;
+            file.change();
+            wait().await;
+            file.rewrite();
+
+            drop(file);
+        });
+
+        // Reading the file for its digest is not reported
+        let mut events = Vec::new();
+        while let Ok(Some(item)) = timeout(stream.next()).await {
+            events.push(item);
+        }
+
+        match events[..] {
+            [FileWatchEvent::Open, FileWatchEvent::Close { writable: true }, FileWatchEvent::Changed { .. }, FileWatchEvent::Open, FileWatchEvent::Close { writable: true }] =>
+                {}
+            _ => panic!(
+                "Expected an open and close with each write, and one content change, got {events:#?}"
+            ),
+        }
+    }
+
+    #[test]
+    async fn shared_subscriptions() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        let mut first = owner
+            .file(file_path)
+            .unwrap()
+            .modify(true)
+            .buffer(1)
+            .share()
+            .await
+            .unwrap();
+
+        wait().await;
+        file.change();
+
+        assert_eq!(
+            timeout(first.next()).await.unwrap(),
+            Some(Ok(FileWatchEvent::Write))
+        );
+
+        // Late subscribers only see new events, and lag is tracked separately for each
+        let mut late = first.clone();
+
+        file.change();
+        wait().await;
+        file.change();
+        wait().await;
+
+        assert!(matches!(
+            timeout(first.next()).await.unwrap(),
+            Some(Err(Lagged(_)))
+        ));
+        assert!(matches!(
+            timeout(late.next()).await.unwrap(),
+            Some(Err(Lagged(_)))
+        ));
+        assert_eq!(
+            timeout(late.next()).await.unwrap(),
+            Some(Ok(FileWatchEvent::Write))
+        );
+
+        drop(late);
+        file.change();
+
+        assert_eq!(
+            timeout(first.next()).await.unwrap(),
+            Some(Ok(FileWatchEvent::Write))
+        );
+    }
+
+    #[test]
+    async fn shared_watch_removed_with_last_subscription() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let _file = TestFile::new(file_path.clone());
+
+        let first = owner
+            .file(file_path)
+            .unwrap()
+            .modify(true)
+            .share()
+            .await
+            .unwrap();
+        let second = first.clone();
+
+        wait().await;
+        let stats = owner.stats();
+        assert_eq!((stats.watches, stats.watchers), (1, 1));
+
+        drop(first);
+        wait().await;
+        assert_eq!(owner.stats().watchers, 1);
+
+        // No events are needed for the watch to be noticed as unused
+        drop(second);
+        wait().await;
+
+        let stats = owner.stats();
+        assert_eq!((stats.watches, stats.watchers), (0, 0));
+    }
+
+    #[test]
+    async fn stats() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        let mut first = owner
+            .file(file_path.clone())
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+        let second = owner
+            .file(file_path)
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+
+        wait().await;
+        let stats = owner.stats();
+        assert_eq!((stats.watches, stats.watchers, stats.events), (1, 2, 0));
+
+        file.change();
+        assert_eq!(
+            timeout(first.next()).await.unwrap(),
+            Some(FileWatchEvent::Write)
+        );
+        assert!(owner.stats().events > 0);
+
+        // Watchers are only noticed to be gone when there is an event for them
+        drop(first);
+        drop(second);
+        file.change();
+        wait().await;
+
+        let stats = owner.stats();
+        assert_eq!((stats.watches, stats.watchers), (0, 0));
+    }
+
+    #[test]
+    async fn next_then_watch() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let mut file = TestFile::new(file_path.clone());
+
+        let fut = timeout(
+            owner
+                .file(file_path.clone())
+                .unwrap()
+                .modify(true)
+                .next()
+                .await
+                .unwrap(),
+        );
+
+        wait().await;
+        file.change();
+
+        assert_eq!(fut.await.unwrap(), Some(FileWatchEvent::Write));
+
+        // The one shot watch is gone, so this must start a new one in the kernel
+        let mut stream = owner
+            .file(file_path)
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+
+        wait().await;
+        file.change();
+
+        assert_eq!(
+            timeout(stream.next()).await.unwrap(),
+            Some(FileWatchEvent::Write)
+        );
+    }
+
+    #[test]
+    async fn symlinks() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+        let file_path = test_dir.path().join("test.txt");
+        let link_path = test_dir.path().join("link.txt");
+        let mut file = TestFile::new(file_path);
+        std::os::unix::fs::symlink(&file.0, &link_path).unwrap();
+
+        let follow = timeout(
+            owner
+                .file(link_path.clone())
+                .unwrap()
+                .modify(true)
+                .next()
+                .await
+                .unwrap(),
+        );
+        let link = timeout(
+            owner
+                .file(link_path)
+                .unwrap()
+                .follow_symlinks(false)
+                .modify(true)
+                .next()
+                .await
+                .unwrap(),
+        );
+
+        wait().await;
+        file.change();
+
+        assert_eq!(follow.await.unwrap(), Some(FileWatchEvent::Write));
+        assert!(link.await.is_err(), "The link itself was not modified");
+
+        let dangling = test_dir.path().join("dangling.txt");
+        std::os::unix::fs::symlink(test_dir.path().join("missing.txt"), &dangling).unwrap();
+
+        let followed = owner.file(dangling.clone()).unwrap().watch().await;
+        assert!(
+            matches!(
+                followed,
+                Err(WatchError::Request(RequestError::DoesNotExist(_)))
+            ),
+            "A dangling link leads nowhere to watch"
+        );
+
+        owner
+            .file(dangling)
+            .unwrap()
+            .follow_symlinks(false)
+            .watch()
+            .await
+            .expect("The dangling link itself can be watched");
+    }
+
+    #[test]
+    async fn shared_watch_options() {
+        let mut owner = crate::new().unwrap();
+        let test_dir = setup_testdir();
+
+        let mut excluding = owner
+            .dir(test_dir.path().into())
+            .unwrap()
+            .excl_unlink(true)
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+        let mut including = owner
+            .dir(test_dir.path().into())
+            .unwrap()
+            .modify(true)
+            .watch()
+            .await
+            .unwrap();
+
+        wait().await;
+        TestFile::new(test_dir.path().join("test.txt")).change();
+
+        assert!(
+            timeout(including.next()).await.unwrap().is_none(),
+            "Can not share a watch which reports unlinked children differently"
+        );
+        assert!(timeout(excluding.next()).await.unwrap().is_some());
+    }
+
     #[test]
     async fn dir_events() {
         let mut owner = crate::new().unwrap();
@@ -151,6 +538,7 @@ mod test {
             .unwrap()
             .modify(true)
             .watch()
+            .await
             .unwrap();
 
         wait().await;
@@ -182,4 +570,41 @@ mod test {
         assert!(got_1);
         assert!(got_2);
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    async fn serde_wire_shape() {
+        use crate::futures::{Digest, DirectoryWatchEvent};
+
+        let event = DirectoryWatchEvent {
+            inner_path: Some("test.txt".into()),
+            event: FileWatchEvent::Changed {
+                previous: Digest(1),
+                current: Digest(u64::MAX),
+            },
+        };
+
+        let json = serde_json::to_value(&event).unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "inner_path": "test.txt",
+                "kind": "changed",
+                "previous": "0000000000000001",
+                "current": "ffffffffffffffff",
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<DirectoryWatchEvent>(json).unwrap(),
+            event
+        );
+
+        let json = serde_json::to_value(FileWatchEvent::Close { writable: true }).unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!({ "kind": "close", "writable": true })
+        );
+    }
 }
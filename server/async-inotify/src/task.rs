@@ -1,4 +1,13 @@
-use std::{collections::HashMap, ffi::OsString, path::PathBuf, time::Duration};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use nix::{
     errno::Errno,
@@ -9,22 +18,41 @@ use tokio::io::Interest;
 use tokio::{
     io::unix::{AsyncFd, AsyncFdReadyGuard},
     select,
+    sync::broadcast::Sender as BroadcastSend,
     sync::mpsc::Receiver as MpscRecv,
     sync::mpsc::{error::TrySendError, Sender as MpscSend},
     sync::oneshot::Receiver as OnceRecv,
     sync::oneshot::Sender as OnceSend,
+    sync::Notify,
     task::JoinHandle,
     time::{interval, Interval},
 };
 
-use crate::futures::DirectoryWatchEvent;
+use crate::{
+    content::ContentState,
+    futures::{DirectoryWatchEvent, FileWatchEvent},
+    handle::IN_EXCL_UNLINK,
+};
+
+/// Counters kept up to date by the watcher task, which are read through [`Handle::stats`]
+///
+/// [`Handle::stats`]: crate::handle::Handle::stats
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) watches: AtomicUsize,
+    pub(crate) watchers: AtomicUsize,
+    pub(crate) events: AtomicU64,
+}
 
 #[derive(Debug)]
 pub(crate) enum WatchRequestInner {
     Start {
         path: PathBuf,
         flags: AddWatchFlags,
+        /// Kernel options which do not select events, such as `IN_ONESHOT`
+        options: AddWatchFlags,
         dir: bool,
+        content: bool,
         sender: Sender,
     },
 
@@ -39,6 +67,7 @@ pub struct WatcherState {
     request_rx: MpscRecv<WatchRequestInner>,
     shutdown: OnceRecv<()>,
     clean_interval: Option<Interval>,
+    released: Arc<Notify>,
     watches: Watches,
 }
 
@@ -51,11 +80,22 @@ pub enum InitError {
     AsyncFd(#[from] std::io::Error),
 }
 
+#[derive(Debug, Error)]
+enum AddError {
+    #[error(transparent)]
+    Inotify(#[from] Errno),
+
+    #[error("It is already watched with different options")]
+    Options,
+}
+
 impl WatcherState {
     pub(crate) fn new(
         request_rx: MpscRecv<WatchRequestInner>,
         shutdown: OnceRecv<()>,
         clean_duration: Option<Duration>,
+        counters: Arc<Counters>,
+        released: Arc<Notify>,
     ) -> Result<Self, InitError> {
         let instance =
             AsyncFd::with_interest(Inotify::init(InitFlags::IN_NONBLOCK)?, Interest::READABLE)?;
@@ -71,7 +111,11 @@ impl WatcherState {
             request_rx,
             shutdown,
             clean_interval,
-            watches: Default::default(),
+            released,
+            watches: Watches {
+                counters,
+                ..Default::default()
+            },
         })
     }
 
@@ -131,6 +175,12 @@ impl WatcherState {
                 }
             }
 
+            _ = self.released.notified() => {
+                self.watches.release(self.instance.get_ref());
+
+                Ok(true)
+            }
+
             _ = maybe(&mut self.clean_interval), if self.watches.dirty => {
                 crate::error!("WOKE UP FOR CLEAN");
 
@@ -167,6 +217,7 @@ impl WatcherState {
 pub(crate) enum Sender {
     Once(OnceSend<DirectoryWatchEvent>),
     Stream(MpscSend<DirectoryWatchEvent>),
+    Broadcast(Arc<BroadcastSend<DirectoryWatchEvent>>),
     None,
 }
 
@@ -175,19 +226,123 @@ struct SingleWatch {
     flags: AddWatchFlags,
     dir: bool,
     remove: bool,
+    content: Option<ContentState>,
     sender: Sender,
 }
 
+impl SingleWatch {
+    /// Events which content tracking needs from the kernel. Read only closes are only asked for
+    /// so that the watch knows when its own reads of the file are over.
+    const CONTENT_FLAGS: AddWatchFlags = AddWatchFlags::IN_MODIFY
+        .union(AddWatchFlags::IN_CLOSE_WRITE)
+        .union(AddWatchFlags::IN_CLOSE_NOWRITE);
+
+    /// The set of events that must be requested from the kernel to serve this watch
+    fn mask(&self) -> AddWatchFlags {
+        if self.content.is_some() {
+            self.flags | Self::CONTENT_FLAGS
+        } else {
+            self.flags
+        }
+    }
+
+    /// Decide what, if anything, this watcher should be sent for an event with `flags`
+    ///
+    /// Reading the file for its digest is recorded in `own`, for a watch with `mask`.
+    fn filter(
+        &mut self,
+        flags: AddWatchFlags,
+        path: &Path,
+        event: &DirectoryWatchEvent,
+        own: &mut OwnEvents,
+        mask: AddWatchFlags,
+    ) -> Vec<DirectoryWatchEvent> {
+        let mut events = Vec::new();
+
+        if let Some(ref mut content) = self.content {
+            // Writes are only reported once they change the contents
+            if flags.contains(AddWatchFlags::IN_MODIFY) {
+                return events;
+            }
+
+            if flags.contains(AddWatchFlags::IN_CLOSE_WRITE) {
+                if flags.intersects(self.flags) {
+                    events.push(event.clone());
+                }
+
+                let (changed, opened) = content.update(path);
+                if opened {
+                    own.read(mask);
+                }
+
+                events.extend(changed.map(|(previous, current)| DirectoryWatchEvent {
+                    inner_path: None,
+                    event: FileWatchEvent::Changed { previous, current },
+                }));
+
+                return events;
+            }
+        }
+
+        if flags.intersects(self.flags) {
+            events.push(event.clone());
+        }
+
+        events
+    }
+}
+
+/// Events the watcher task has caused itself by reading a file for its digest, which are
+/// dropped rather than reported
+///
+/// The kernel does not say who caused an event, so the events of each read are counted off as
+/// they arrive. Reads of the file between one of our opens and its close are taken to be ours.
+#[derive(Debug, Default)]
+struct OwnEvents {
+    opens: u32,
+    closes: u32,
+}
+
+impl OwnEvents {
+    /// Expect the events of one read of the file, by a watch with `mask`
+    fn read(&mut self, mask: AddWatchFlags) {
+        if mask.contains(AddWatchFlags::IN_OPEN) {
+            self.opens += 1;
+        }
+        if mask.contains(AddWatchFlags::IN_CLOSE_NOWRITE) {
+            self.closes += 1;
+        }
+    }
+
+    /// Whether an event with `flags` was caused by us, counting it off if so
+    fn take(&mut self, flags: AddWatchFlags) -> bool {
+        if flags.contains(AddWatchFlags::IN_OPEN) && self.opens > 0 {
+            self.opens -= 1;
+            return true;
+        }
+
+        if flags.contains(AddWatchFlags::IN_CLOSE_NOWRITE) && self.closes > 0 {
+            self.closes -= 1;
+            return true;
+        }
+
+        flags.contains(AddWatchFlags::IN_ACCESS) && self.closes > 0
+    }
+}
+
 #[derive(Debug)]
 struct WatchState {
     path: PathBuf,
+    mask: AddWatchFlags,
     watchers: Vec<SingleWatch>,
+    own: OwnEvents,
 }
 
 #[derive(Debug, Default)]
 struct Watches {
     watches: HashMap<WatchDescriptor, WatchState>,
     paths: HashMap<PathBuf, WatchDescriptor>,
+    counters: Arc<Counters>,
     pub dirty: bool,
 }
 
@@ -202,21 +357,34 @@ impl Watches {
         //   and we were woken by the executor with readable
         let events = guard.get_inner().read_events()?;
 
+        self.counters
+            .events
+            .fetch_add(events.len() as u64, Ordering::Relaxed);
+
         for event in events.into_iter() {
             eprintln!("Got Event");
             let flags = event.mask;
-            let path = event
-                .name
-                .map(OsString::into_string)
-                .map(Result::ok)
-                .flatten();
+            let wd = event.wd;
+            let path = event.name.map(OsString::into_string).and_then(Result::ok);
+
+            if flags.contains(AddWatchFlags::IN_IGNORED) {
+                // The kernel has removed this watch, because it was one shot, we removed it, or the
+                // inode is gone. Dropping the watchers closes all of their senders.
+                self.forget(wd);
+                continue;
+            }
 
-            if let Some(watch) = self.watches.get_mut(&event.wd) {
+            if let Some(watch) = self.watches.get_mut(&wd) {
                 eprintln!(
                     "Got event for path: {} with flags {flags:4X}",
                     watch.path.display()
                 );
 
+                // Only events about the file itself can come from reading it
+                if path.is_none() && watch.own.take(flags) {
+                    continue;
+                }
+
                 let event = flags.try_into();
                 if event.is_err() {
                     eprintln!("Got unexpected Flags: 0x{flags:8X}");
@@ -236,44 +404,65 @@ impl Watches {
                         continue;
                     }
 
-                    if !flags.intersects(watcher.flags) {
-                        continue;
-                    }
-
-                    // We know that this is an event that they want
-                    // So take the sender, send, and replace the sender if necessary
+                    let events =
+                        watcher.filter(flags, &watch.path, &event, &mut watch.own, watch.mask);
 
-                    let mut replace = std::mem::replace(&mut watcher.sender, Sender::None);
+                    for event in events {
+                        // We know that this is an event that they want
+                        // So take the sender, send, and replace the sender if necessary
 
-                    replace = match replace {
-                        Sender::Once(sender) => {
-                            let _ = sender.send(event.clone());
+                        let mut replace = std::mem::replace(&mut watcher.sender, Sender::None);
 
-                            watcher.remove = true;
-                            self.dirty = true;
+                        replace = match replace {
+                            Sender::Once(sender) => {
+                                let _ = sender.send(event.clone());
 
-                            // send consumes sender, so we cannot defer drop
-                            Sender::None
-                        }
-                        Sender::Stream(sender) => {
-                            if let Err(TrySendError::Closed(_)) = sender.try_send(event.clone()) {
                                 watcher.remove = true;
                                 self.dirty = true;
 
-                                // we defer cleaning up the actual sender
+                                // send consumes sender, so we cannot defer drop
+                                Sender::None
                             }
+                            Sender::Stream(sender) => {
+                                if let Err(TrySendError::Closed(_)) = sender.try_send(event.clone())
+                                {
+                                    watcher.remove = true;
+                                    self.dirty = true;
 
-                            Sender::Stream(sender)
-                        }
-                        otherwise => otherwise,
-                    };
+                                    // we defer cleaning up the actual sender
+                                }
+
+                                Sender::Stream(sender)
+                            }
+                            Sender::Broadcast(sender) => {
+                                // Only fails when there are no subscriptions left to receive it
+                                if sender.send(event.clone()).is_err() {
+                                    watcher.remove = true;
+                                    self.dirty = true;
+                                }
+
+                                Sender::Broadcast(sender)
+                            }
+                            otherwise => otherwise,
+                        };
+
+                        std::mem::swap(&mut replace, &mut watcher.sender);
+                    }
+                }
+
+                watch.watchers.retain(|it| !it.remove);
 
-                    std::mem::swap(&mut replace, &mut watcher.sender);
+                if watch.watchers.is_empty() {
+                    // The kernel follows up with IN_IGNORED, which is when we forget the watch.
+                    // One shot watches are already gone, so this is allowed to fail.
+                    let _ = guard.get_inner().rm_watch(wd);
                 }
             }
         }
 
         guard.clear_ready();
+        self.count();
+
         Ok(())
     }
 
@@ -289,32 +478,143 @@ impl Watches {
             WatchRequestInner::Start {
                 path,
                 flags,
+                options,
                 dir,
+                content,
                 sender,
             } => {
                 let watch = SingleWatch {
                     flags,
                     dir,
                     remove: false,
+                    content: content.then(ContentState::default),
                     sender,
                 };
+                let mask = watch.mask() | options;
+
+                match self.add(inotify, path.clone(), mask, watch) {
+                    // The first digest is taken once the watch is in place, so that the events
+                    // of reading the file are expected
+                    Ok(wd) if content => {
+                        let (loaded, opened) = ContentState::load(&path);
+                        let state = self.watches.get_mut(&wd).unwrap();
 
-                if let Some(wd) = self.paths.get(&path) {
-                    let state = self.watches.get_mut(wd).unwrap();
-                    state.watchers.push(watch);
-                } else {
-                    let wd = inotify.add_watch(&path, flags)?;
-                    let state = WatchState {
-                        path: path.clone(),
-                        watchers: Vec::from([watch]),
-                    };
-
-                    self.paths.insert(path, wd);
-                    self.watches.insert(wd, state);
+                        if opened {
+                            state.own.read(state.mask);
+                        }
+                        if let Some(watcher) = state.watchers.last_mut() {
+                            watcher.content = Some(loaded);
+                        }
+                    }
+                    Ok(_) => {}
+                    // Dropping the watch closes its sender, which ends the requester's stream
+                    Err(e) => {
+                        crate::warn!("Could not watch {}: {e}", path.display());
+                    }
                 }
             }
         };
 
+        self.count();
+
         Ok(())
     }
+
+    /// Kernel options which only change how the path is looked up when it is added, and so are
+    /// left out when a watch is shared
+    const LOOKUP: AddWatchFlags = AddWatchFlags::IN_ONLYDIR.union(AddWatchFlags::IN_DONT_FOLLOW);
+
+    /// Kernel options which change the events of everyone on a watch, and so must be the same for
+    /// a watch to be shared
+    const SHARED: AddWatchFlags = IN_EXCL_UNLINK;
+
+    fn add(
+        &mut self,
+        inotify: &Inotify,
+        path: PathBuf,
+        mask: AddWatchFlags,
+        watch: SingleWatch,
+    ) -> Result<WatchDescriptor, AddError> {
+        let (wd, replaced) = match self.paths.get(&path) {
+            Some(wd) => (*wd, false),
+            None => {
+                let wd = inotify.add_watch(&path, mask)?;
+
+                match self.watches.entry(wd) {
+                    Entry::Vacant(entry) => {
+                        self.paths.insert(path.clone(), wd);
+                        entry.insert(WatchState {
+                            path,
+                            mask,
+                            watchers: Vec::from([watch]),
+                            own: OwnEvents::default(),
+                        });
+
+                        return Ok(wd);
+                    }
+
+                    // Another path led to an inode we are already watching, and the kernel has
+                    // just replaced that watch's mask with ours, so it is put back below
+                    Entry::Occupied(_) => (wd, true),
+                }
+            }
+        };
+
+        let state = self.watches.get_mut(&wd).unwrap();
+
+        if state.mask & Self::SHARED != mask & Self::SHARED {
+            if replaced {
+                inotify.add_watch(&state.path, state.mask)?;
+            }
+
+            return Err(AddError::Options);
+        }
+
+        // Adding a watch for an inode that is already watched replaces the mask for that watch
+        // descriptor, so widen it to cover every watcher. A shared watch can not be one shot, as
+        // the first event would remove it for everyone.
+        let widened = (state.mask | (mask - Self::LOOKUP)) - AddWatchFlags::IN_ONESHOT;
+        if replaced || widened != state.mask {
+            state.mask = widened;
+            inotify.add_watch(&state.path, state.mask)?;
+        }
+
+        self.paths.insert(path, wd);
+        state.watchers.push(watch);
+
+        Ok(wd)
+    }
+
+    /// Remove shared watchers which have no subscriptions left, and any kernel watches that were
+    /// only kept for them
+    fn release(&mut self, inotify: &Inotify) {
+        for (wd, watch) in self.watches.iter_mut() {
+            watch.watchers.retain(|it| match it.sender {
+                Sender::Broadcast(ref sender) => sender.receiver_count() > 0,
+                _ => true,
+            });
+
+            if watch.watchers.is_empty() {
+                // Forgotten once the kernel follows up with IN_IGNORED
+                let _ = inotify.rm_watch(*wd);
+            }
+        }
+
+        self.count();
+    }
+
+    /// Update the counters of watches and watchers
+    fn count(&self) {
+        let watchers = self.watches.values().map(|it| it.watchers.len()).sum();
+
+        self.counters
+            .watches
+            .store(self.watches.len(), Ordering::Relaxed);
+        self.counters.watchers.store(watchers, Ordering::Relaxed);
+    }
+
+    fn forget(&mut self, wd: WatchDescriptor) {
+        self.watches.remove(&wd);
+        self.paths.retain(|_, it| *it != wd);
+    }
 }
@@ -1,12 +1,22 @@
 const PROTOS: &[&str] = &[
     "proto/helloworld/helloworld.proto",
     "proto/event/event.proto",
+    "proto/files/files.proto",
 ];
 const INCLUDES: &[&str] = &["proto"];
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR")?);
+
     tonic_build::configure()
         .build_client(false)
+        // Served by reflection, for tools such as grpcurl
+        .file_descriptor_set_path(out_dir.join("mcmanager_descriptor.bin"))
+        // Events are also sent outside of gRPC, such as to webhooks and the audit log
+        .type_attribute(
+            ".event",
+            "#[cfg_attr(feature = \"serde\", derive(serde_impl::Serialize, serde_impl::Deserialize), serde(crate = \"serde_impl\", rename_all = \"snake_case\"))]",
+        )
         .compile(PROTOS, INCLUDES)?;
 
     Ok(())
@@ -0,0 +1,164 @@
+//! File watch events, and conversions from the events produced by [`async_inotify`]
+
+use async_inotify::futures::{DirectoryWatchEvent, FileWatchEvent};
+
+mod proto {
+    tonic::include_proto!("files");
+}
+
+doc_inline! {
+    pub use proto::Digest;
+    pub use proto::FileEvent;
+    pub use proto::file_event::Kind as FileEventKind;
+    pub use proto::WatchSubscription;
+    pub use proto::files_server::Files;
+}
+
+use proto::files_server::FilesServer;
+server!(FilesServer, Files);
+
+impl From<async_inotify::futures::Digest> for Digest {
+    fn from(it: async_inotify::futures::Digest) -> Self {
+        Self { value: it.0 }
+    }
+}
+
+impl From<Digest> for async_inotify::futures::Digest {
+    fn from(it: Digest) -> Self {
+        Self(it.value)
+    }
+}
+
+impl From<FileWatchEvent> for FileEvent {
+    fn from(it: FileWatchEvent) -> Self {
+        let mut event = FileEvent::default();
+
+        match it {
+            FileWatchEvent::Read => event.set_kind(FileEventKind::Read),
+            FileWatchEvent::Write => event.set_kind(FileEventKind::Write),
+            FileWatchEvent::Open => event.set_kind(FileEventKind::Open),
+            FileWatchEvent::Close { writable } => {
+                event.set_kind(FileEventKind::Close);
+                event.writable = writable;
+            }
+            FileWatchEvent::Changed { previous, current } => {
+                event.set_kind(FileEventKind::Changed);
+                event.previous = Some(previous.into());
+                event.current = Some(current.into());
+            }
+        }
+
+        event
+    }
+}
+
+impl From<DirectoryWatchEvent> for FileEvent {
+    fn from(it: DirectoryWatchEvent) -> Self {
+        FileEvent {
+            inner_path: it.inner_path.unwrap_or_default(),
+            ..it.event.into()
+        }
+    }
+}
+
+impl TryFrom<FileEvent> for DirectoryWatchEvent {
+    type Error = String;
+
+    fn try_from(it: FileEvent) -> Result<Self, Self::Error> {
+        let event = match it.kind() {
+            FileEventKind::Unknown => {
+                return Err(format!("FileEvent has unknown kind {}", it.kind))
+            }
+            FileEventKind::Read => FileWatchEvent::Read,
+            FileEventKind::Write => FileWatchEvent::Write,
+            FileEventKind::Open => FileWatchEvent::Open,
+            FileEventKind::Close => FileWatchEvent::Close {
+                writable: it.writable,
+            },
+            FileEventKind::Changed => match (it.previous, it.current) {
+                (Some(previous), Some(current)) => FileWatchEvent::Changed {
+                    previous: previous.into(),
+                    current: current.into(),
+                },
+                _ => return Err("FileEvent of kind CHANGED is missing a digest".into()),
+            },
+        };
+
+        Ok(DirectoryWatchEvent {
+            inner_path: Some(it.inner_path).filter(|it| !it.is_empty()),
+            event,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use async_inotify::futures::{Digest, DirectoryWatchEvent, FileWatchEvent};
+
+    use super::{FileEvent, FileEventKind};
+
+    fn round_trip(event: DirectoryWatchEvent) -> DirectoryWatchEvent {
+        FileEvent::from(event).try_into().unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        let events = [
+            FileWatchEvent::Read,
+            FileWatchEvent::Write,
+            FileWatchEvent::Open,
+            FileWatchEvent::Close { writable: true },
+            FileWatchEvent::Close { writable: false },
+            FileWatchEvent::Changed {
+                previous: Digest(1),
+                current: Digest(u64::MAX),
+            },
+        ];
+
+        for event in events {
+            for inner_path in [None, Some(String::from("world/level.dat"))] {
+                let event = DirectoryWatchEvent { inner_path, event };
+                assert_eq!(round_trip(event.clone()), event);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_incomplete_events() {
+        let unknown = FileEvent::default();
+        assert!(DirectoryWatchEvent::try_from(unknown).is_err());
+
+        let mut changed = FileEvent::from(FileWatchEvent::Changed {
+            previous: Digest(1),
+            current: Digest(2),
+        });
+        assert_eq!(changed.kind(), FileEventKind::Changed);
+        changed.current = None;
+        assert!(DirectoryWatchEvent::try_from(changed).is_err());
+    }
+
+    /// Passing through the proto must not change how an event is written as JSON, which
+    /// async-inotify checks the shape of
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_wire_shape() {
+        let events = [
+            FileWatchEvent::Close { writable: true },
+            FileWatchEvent::Changed {
+                previous: Digest(1),
+                current: Digest(u64::MAX),
+            },
+        ];
+
+        for event in events {
+            for inner_path in [None, Some(String::from("world/level.dat"))] {
+                let event = DirectoryWatchEvent { inner_path, event };
+
+                assert_eq!(
+                    serde_json::to_value(round_trip(event.clone())).unwrap(),
+                    serde_json::to_value(event).unwrap()
+                );
+            }
+        }
+    }
+}
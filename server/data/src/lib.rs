@@ -43,6 +43,9 @@ macro_rules! server {
     };
 }
 
+/// Encoded descriptors of every service and message, for gRPC reflection
+pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("mcmanager_descriptor");
+
 pub mod events {
 
     mod proto {
@@ -54,15 +57,50 @@ pub mod events {
         /// Serve an Events Service
         pub use proto::Event as EventResponse;
         pub use proto::SystemSnapshot;
+        pub use proto::LoadAverage;
+        pub use proto::DiskUsage;
+        pub use proto::NetworkUsage;
+        pub use proto::ProcessSnapshot;
+        pub use proto::Pressure;
+        pub use proto::PressureStall;
+        pub use proto::CgroupUsage;
+        pub use proto::AlertRaised;
+        pub use proto::AlertCleared;
+        pub use proto::GcEvent;
+        pub use proto::GcStats;
+        pub use proto::Gap;
+        pub use proto::Heartbeat;
+        pub use proto::Batch;
         pub use proto::EventSubscription;
+        pub use proto::event_subscription::Topic;
+        pub use proto::event_subscription::RateLimit;
+        pub use proto::Severity;
+        pub use proto::SamplingSettings;
+        pub use proto::HistoryRequest;
+        pub use proto::history_request::Resolution;
+        pub use proto::HistoryResponse;
         pub use proto::event::Event;
         pub use proto::events_server::Events;
+        pub use proto::Subscriber;
+        pub use proto::ListSubscribersRequest;
+        pub use proto::ListSubscribersResponse;
+        pub use proto::DisconnectRequest;
+        pub use proto::DisconnectResponse;
+        pub use proto::AuditQuery;
+        pub use proto::AuditEntry;
+        pub use proto::AuditResponse;
+        pub use proto::events_admin_server::EventsAdmin;
     }
 
     use proto::events_server::EventsServer;
     server!(EventsServer, Events);
+
+    use proto::events_admin_server::EventsAdminServer;
+    server!(EventsAdminServer, EventsAdmin);
 }
 
+pub mod files;
+
 pub mod hello {
     mod proto {
         tonic::include_proto!("helloworld");
@@ -1,8 +1,14 @@
 //! Application level setup and configuration
 
+use std::collections::HashMap;
 use std::fmt::Debug;
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use serde::Deserialize;
+
+use crate::prelude::*;
 use tracing_subscriber::{
     filter::{LevelFilter, Targets},
     prelude::*,
@@ -11,6 +17,7 @@ use tracing_subscriber::{
 };
 
 pub const DEFAULT_CONFIG_LOCATION: &str = "./server.config.yml";
+pub const DEFAULT_DATA_DIR: &str = "./state";
 
 #[derive(clap::Parser, Clone)]
 pub struct Args {
@@ -40,6 +47,410 @@ impl Debug for Args {
     }
 }
 
+/// Server configuration, read from [`Args::config_location_or_default`]
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Directory the server keeps its own state in, such as metric history
+    pub data_dir: PathBuf,
+    /// Minecraft server instances managed by this server, by id
+    pub instances: HashMap<String, InstanceConfig>,
+    pub auth: AuthConfig,
+    pub sysinfo: SysinfoConfig,
+    /// Rules which raise alerts on the Events service
+    pub alerts: Vec<AlertRule>,
+    pub prometheus: PrometheusConfig,
+    pub events: EventsConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            instances: HashMap::new(),
+            auth: AuthConfig::default(),
+            sysinfo: SysinfoConfig::default(),
+            alerts: Vec::new(),
+            prometheus: PrometheusConfig::default(),
+            events: EventsConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceConfig {
+    /// Directory the instance is run from. Remote clients may not reach files outside of it.
+    ///
+    /// The instance's server process is found by looking for the outermost process with this as
+    /// its working directory.
+    pub root: PathBuf,
+
+    /// Unified JVM GC log the server writes, relative to [`root`](Self::root)
+    ///
+    /// Instances which are [`launch`](Self::launch)ed are given the flag from [`gc_log_flag`] to
+    /// write it. Anything else has to be started with that flag for pauses to be reported.
+    ///
+    /// [`gc_log_flag`]: crate::information::gc_log_flag
+    #[serde(default = "InstanceConfig::default_gc_log")]
+    pub gc_log: PathBuf,
+
+    /// Start the server when the manager starts, leave out when it is started some other way
+    #[serde(default)]
+    pub launch: Option<LaunchConfig>,
+}
+
+impl InstanceConfig {
+    fn default_gc_log() -> PathBuf {
+        PathBuf::from("logs/gc.log")
+    }
+}
+
+/// How to run the server of an instance, which is run from its root as
+/// `<java> <jvm_args> -jar <jar> <args>`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LaunchConfig {
+    /// Looked up on the `PATH` unless it is a path
+    #[serde(default = "LaunchConfig::default_java")]
+    pub java: PathBuf,
+    /// Such as `-Xmx4G`. GC logging is added unless these already set it up with `-Xlog:gc`.
+    #[serde(default)]
+    pub jvm_args: Vec<String>,
+    /// Server jar, relative to the instance root
+    pub jar: PathBuf,
+    /// Arguments to the server itself
+    #[serde(default = "LaunchConfig::default_args")]
+    pub args: Vec<String>,
+}
+
+impl LaunchConfig {
+    fn default_java() -> PathBuf {
+        PathBuf::from("java")
+    }
+
+    fn default_args() -> Vec<String> {
+        Vec::from(["nogui".to_owned()])
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub users: Vec<UserConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+    /// Bearer token the user authenticates with
+    pub token: String,
+    /// Whether the user may call admin services
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl Debug for UserConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Keep tokens out of the logs
+        f.debug_struct("UserConfig")
+            .field("name", &self.name)
+            .field("admin", &self.admin)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SysinfoConfig {
+    /// Time between samples while anyone is subscribed, such as `3s` or `500ms`
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+
+    /// Groups of metrics to collect, anything left out is reported as zero
+    pub metrics: Vec<MetricGroup>,
+
+    pub history: HistoryConfig,
+}
+
+impl Default for SysinfoConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(3),
+            metrics: Vec::from([
+                MetricGroup::Cpu,
+                MetricGroup::Memory,
+                MetricGroup::Disks,
+                MetricGroup::Networks,
+                MetricGroup::Processes,
+                MetricGroup::Cgroups,
+                MetricGroup::Pressure,
+                MetricGroup::Gc,
+            ]),
+            history: HistoryConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Keep an hour of samples and minute averages for the History rpc, which keeps sampling even
+    /// while nobody is subscribed
+    pub enabled: bool,
+
+    /// How long minute averages are kept for, such as `7d`
+    #[serde(with = "humantime_serde")]
+    pub retention: Duration,
+
+    /// Save history under [`Config::data_dir`], so that it is reloaded after a restart
+    pub persist: bool,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retention: Duration::from_secs(7 * 24 * 60 * 60),
+            persist: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricGroup {
+    /// Per core and total CPU usage, and load averages
+    Cpu,
+    /// Memory and swap usage
+    Memory,
+    /// Space used on each mounted disk
+    Disks,
+    /// Receive and transmit rates of each network interface
+    Networks,
+    /// Resource usage of each managed instance's server process
+    Processes,
+    /// Limits and usage of the cgroup v2 control groups of the manager and of each server
+    /// process. Memory pressure is reported against the manager's limit when it has one.
+    Cgroups,
+    /// System wide pressure stall information, from `/proc/pressure`
+    Pressure,
+    /// Garbage collection pauses of each managed instance, read from its JVM GC log. Rolling
+    /// statistics are only reported along with processes.
+    Gc,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrometheusConfig {
+    /// Address to serve metrics at `/metrics` on, such as `0.0.0.0:9100`. Not served when unset.
+    pub listen: Option<SocketAddr>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    /// Time a subscription may go without events before a heartbeat is sent, which is also the
+    /// interval of TCP and HTTP/2 keepalives, such as `15s`. Must be longer than zero.
+    #[serde(with = "humantime_serde")]
+    pub heartbeat: Duration,
+    /// URLs which are sent events as they happen
+    pub webhooks: Vec<WebhookConfig>,
+    pub audit: AuditConfig,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat: Duration::from_secs(15),
+            webhooks: Vec::new(),
+            audit: AuditConfig::default(),
+        }
+    }
+}
+
+/// Writes events to JSON lines files under [`Config::data_dir`], which can be searched with
+/// `EventsAdmin.QueryAudit`
+///
+/// The log is split into segments. Old segments are compressed with gzip, and deleted once they
+/// are past the retention.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    /// Every topic when empty
+    pub topics: Vec<EventTopic>,
+    /// Every instance when empty. Events which are not about an instance are written either way.
+    pub instances: Vec<String>,
+    pub min_severity: EventSeverity,
+    /// Start a new segment once the current one is this many bytes
+    pub segment_bytes: u64,
+    /// Start a new segment once the current one is this old, such as `1d`
+    #[serde(with = "humantime_serde")]
+    pub segment_age: Duration,
+    /// Delete segments once everything in them is this old, such as `90d`
+    #[serde(with = "humantime_serde")]
+    pub retention: Duration,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            topics: Vec::new(),
+            instances: Vec::new(),
+            min_severity: EventSeverity::Info,
+            segment_bytes: 16 * 1024 * 1024,
+            segment_age: Duration::from_secs(24 * 60 * 60),
+            retention: Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// Topics of events, as in `EventSubscription.Topic`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventTopic {
+    System,
+    Process,
+    Alert,
+    Gc,
+}
+
+/// How much an event needs attention, as in the `Severity` of the Events service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventSeverity {
+    /// Snapshots, which are taken every few seconds
+    Debug,
+    Info,
+    Warning,
+}
+
+/// POSTs each event which matches its filter to a URL, as JSON
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Name the hook is logged with
+    pub name: String,
+    /// Such as `https://chat.example.com/hooks/mcmanager`
+    pub url: String,
+    /// Key each body is signed with, using HMAC-SHA256 over `{timestamp}.{body}`. The signature is
+    /// sent as `x-mcmanager-signature: sha256=<hex>`, and the unix time it covers as
+    /// `x-mcmanager-timestamp`.
+    pub secret: Option<String>,
+    /// Every topic when empty
+    #[serde(default)]
+    pub topics: Vec<EventTopic>,
+    /// Every instance when empty. Events which are not about an instance are sent either way.
+    #[serde(default)]
+    pub instances: Vec<String>,
+    /// Snapshots are only sent when this is `debug`
+    #[serde(default = "WebhookConfig::default_min_severity")]
+    pub min_severity: EventSeverity,
+    /// Attempts after the first before an event is dropped
+    #[serde(default = "WebhookConfig::default_retries")]
+    pub retries: u32,
+    /// Time before the first retry, which doubles for each retry after it, such as `1s`
+    #[serde(default = "WebhookConfig::default_backoff", with = "humantime_serde")]
+    pub backoff: Duration,
+}
+
+impl WebhookConfig {
+    fn default_min_severity() -> EventSeverity {
+        EventSeverity::Info
+    }
+
+    fn default_retries() -> u32 {
+        5
+    }
+
+    fn default_backoff() -> Duration {
+        Duration::from_secs(1)
+    }
+}
+
+impl Debug for WebhookConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Keep secrets out of the logs, and URLs too since they often have a token in them
+        f.debug_struct("WebhookConfig")
+            .field("name", &self.name)
+            .field("topics", &self.topics)
+            .field("instances", &self.instances)
+            .field("min_severity", &self.min_severity)
+            .field("retries", &self.retries)
+            .field("backoff", &self.backoff)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Raises an alert when a metric crosses a threshold for long enough, and clears it once the
+/// metric is back past the clear threshold
+///
+/// Exactly one of `above` and `below` must be set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    /// Name alerts from this rule are reported with
+    pub name: String,
+    pub metric: AlertMetric,
+    /// Raise when the metric is above this
+    pub above: Option<f64>,
+    /// Raise when the metric is below this
+    pub below: Option<f64>,
+    /// Clear once the metric is back past this, which defaults to the threshold. Set it a bit
+    /// inside of the threshold so that a metric hovering around it does not flap.
+    pub clear: Option<f64>,
+    /// How long the threshold must stay crossed before the alert is raised, such as `2m`
+    #[serde(rename = "for", default, with = "humantime_serde")]
+    pub duration: Duration,
+    /// How long after clearing before the alert may be raised again
+    #[serde(default, with = "humantime_serde")]
+    pub cooldown: Duration,
+}
+
+/// Snapshot fields which alert rules can check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMetric {
+    /// Usage of all cores together, from 0 to 1
+    CpuUsage,
+    /// One minute load average
+    LoadOne,
+    /// Fraction of memory in use, from 0 to 1
+    MemoryUsage,
+    MemoryUsedBytes,
+    SwapUsedBytes,
+    /// Free space on each disk
+    DiskAvailableBytes,
+    /// Fraction of each disk in use, from 0 to 1
+    DiskUsage,
+    /// Receive rate of each network interface
+    NetworkRxBytesPerSecond,
+    /// Transmit rate of each network interface
+    NetworkTxBytesPerSecond,
+    /// CPU usage of each instance, 100 for each fully used core
+    InstanceCpuUsage,
+    /// Resident memory of each instance
+    InstanceRssBytes,
+}
+
+impl Config {
+    /// Read the config file at `path`, using the defaults if there is no file there
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => serde_yaml::from_str(&contents)
+                .with_context(|| format!("Parsing config file {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::warn!("No config file at {}, using defaults", path.display());
+                Ok(Self::default())
+            }
+            Err(e) => Err(e).with_context(|| format!("Reading config file {}", path.display())),
+        }
+    }
+}
+
 pub fn init_tracing() {
     let log_layer = tracing_subscriber::fmt::layer().with_filter(
         // TODO(josiah) monitor upstream for or contribute a from_env/from_env_default for
@@ -0,0 +1,609 @@
+//! Audit log of events, kept as JSON lines files in the data directory
+//!
+//! The log is split into segments named after when they were started, in milliseconds since the
+//! unix epoch. Only the latest segment is written to. Once it is big or old enough it is compressed
+//! with gzip and a new one is started, and compressed segments are deleted once everything in them
+//! is past the retention.
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use data::events::Event;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    application::{AuditConfig, Config},
+    bus::{BusSubscription, EventBus, Filter, Sequenced},
+    information::{self, SystemInfo},
+    prelude::*,
+};
+
+/// An event as it is written to the log, one per line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// When the event was written
+    pub unixtime: u64,
+    pub sequence: u64,
+    /// Of the bus which numbered `sequence`, zero for entries written before epochs were kept
+    #[serde(default)]
+    pub epoch: u64,
+    pub event: Event,
+}
+
+/// Entries returned by a query which does not set a limit
+pub const DEFAULT_LIMIT: usize = 1000;
+
+/// Most entries a query may return, so that responses stay well within gRPC's message limit
+pub const MAX_LIMIT: usize = 2000;
+
+/// Searches the audit log
+#[derive(Debug, Clone)]
+pub struct Audit {
+    dir: PathBuf,
+}
+
+/// What to search the audit log for
+pub struct Query {
+    /// Unix time stamps of the earliest and latest entries to return
+    pub from: u64,
+    pub to: u64,
+    pub filter: Filter,
+    /// Most entries to return, the latest ones are kept
+    pub limit: usize,
+}
+
+/// Start writing events to the audit log, if it is enabled
+pub fn start_audit(config: &Config, info: &SystemInfo, bus: &EventBus) -> Result<Option<Audit>> {
+    let audit = &config.events.audit;
+
+    if !audit.enabled {
+        return Ok(None);
+    }
+
+    let dir = config.data_dir.join("audit");
+    let writer = Writer::open(&dir, audit, now_ms())?;
+    let filter = Filter::from_config(&audit.topics, &audit.instances, audit.min_severity);
+
+    let sampling = filter.sampling(info);
+    let events = bus.subscribe();
+
+    tokio::task::Builder::new()
+        .name("Audit log")
+        .spawn(async move {
+            let _sampling = sampling;
+            write_events(writer, filter, events).await
+        });
+
+    Ok(Some(Audit { dir }))
+}
+
+async fn write_events(mut writer: Writer, filter: Filter, mut events: BusSubscription) {
+    info!("Writing Audit Log to {}", writer.dir.display());
+
+    let epoch = events.epoch();
+
+    while let Some(event) = events.next().await {
+        let Sequenced { sequence, event } = match event {
+            Ok(event) if filter.matches(&event.event) => event,
+            Ok(_) => continue,
+            Err(gap) => {
+                warn!(
+                    "Audit Log Skipped {} Events, Writing Fell Behind",
+                    gap.next - gap.after - 1
+                );
+                continue;
+            }
+        };
+
+        let entry = Entry {
+            unixtime: information::unixtime(),
+            sequence,
+            epoch,
+            event,
+        };
+
+        // Files are written to from a blocking thread, and the writer is passed back after
+        writer = match tokio::task::spawn_blocking(move || {
+            if let Err(e) = writer.write(&entry, now_ms()) {
+                warn!("Could not write to the audit log: {e:#}");
+            }
+
+            writer
+        })
+        .await
+        {
+            Ok(writer) => writer,
+            Err(e) => {
+                warn!("Audit log writer panicked, no more events will be written: {e}");
+                return;
+            }
+        };
+    }
+}
+
+impl Audit {
+    /// Entries which match `query`, oldest first, and whether earlier ones were left out for its
+    /// limit
+    pub async fn query(&self, query: Query) -> Result<(Vec<Entry>, bool)> {
+        let dir = self.dir.clone();
+
+        tokio::task::spawn_blocking(move || search(&dir, &query))
+            .await
+            .context("Searching the audit log")?
+    }
+}
+
+fn search(dir: &Path, query: &Query) -> Result<(Vec<Entry>, bool)> {
+    let segments = segments(dir)?;
+    let mut found = VecDeque::new();
+    let mut truncated = false;
+
+    for (index, segment) in segments.iter().enumerate() {
+        if segment.started / 1000 > query.to {
+            break;
+        }
+
+        // Everything in a segment is from before the next one was started
+        let ended = segments.get(index + 1).map(|it| it.started / 1000);
+        if ended.is_some_and(|it| it < query.from) {
+            continue;
+        }
+
+        let file = File::open(&segment.path)
+            .with_context(|| format!("Opening audit log segment {}", segment.path.display()))?;
+        let reader: Box<dyn Read> = if segment.compressed {
+            Box::new(GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+
+        // Lines which are cut off, from a crash or from being written to right now, are skipped
+        let entries = BufReader::new(reader)
+            .split(b'\n')
+            .map_while(|it| it.ok())
+            .filter_map(|it| serde_json::from_slice::<Entry>(&it).ok());
+
+        for entry in entries {
+            if entry.unixtime < query.from
+                || entry.unixtime > query.to
+                || !query.filter.matches(&entry.event)
+            {
+                continue;
+            }
+
+            found.push_back(entry);
+
+            if found.len() > query.limit {
+                found.pop_front();
+                truncated = true;
+            }
+        }
+    }
+
+    Ok((found.into(), truncated))
+}
+
+struct Segment {
+    path: PathBuf,
+    /// Milliseconds since the unix epoch
+    started: u64,
+    compressed: bool,
+}
+
+/// Every segment in `dir`, oldest first
+fn segments(dir: &Path) -> Result<Vec<Segment>> {
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Listing audit log directory {}", dir.display()))?;
+
+    let mut segments = entries
+        .filter_map(|it| it.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let name = name.strip_prefix("events-")?;
+
+            let (started, compressed) = match name.strip_suffix(".jsonl.gz") {
+                Some(started) => (started, true),
+                None => (name.strip_suffix(".jsonl")?, false),
+            };
+
+            Some(Segment {
+                path: entry.path(),
+                started: started.parse().ok()?,
+                compressed,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    segments.sort_by_key(|it| it.started);
+
+    Ok(segments)
+}
+
+/// Appends entries to the latest segment
+struct Writer {
+    dir: PathBuf,
+    current: Option<Current>,
+    segment_bytes: u64,
+    segment_age: Duration,
+    retention: Duration,
+}
+
+struct Current {
+    path: PathBuf,
+    file: File,
+    started: u64,
+    bytes: u64,
+}
+
+impl Writer {
+    /// Carry on with the latest segment in `dir`, compressing any others which were left
+    /// uncompressed
+    fn open(dir: &Path, config: &AuditConfig, now: u64) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Creating audit log directory {}", dir.display()))?;
+
+        let mut writer = Self {
+            dir: dir.to_owned(),
+            current: None,
+            segment_bytes: config.segment_bytes,
+            segment_age: config.segment_age,
+            retention: config.retention,
+        };
+
+        let mut uncompressed = segments(dir)?
+            .into_iter()
+            .filter(|it| !it.compressed)
+            .collect::<Vec<_>>();
+
+        if let Some(latest) = uncompressed.pop() {
+            writer.current = Some(Current::open(latest.path, latest.started)?);
+        }
+
+        for segment in uncompressed {
+            compress(&segment.path)?;
+        }
+
+        writer.prune(now)?;
+
+        Ok(writer)
+    }
+
+    /// Append an entry, first starting a new segment if the current one is big or old enough
+    fn write(&mut self, entry: &Entry, now: u64) -> Result<()> {
+        let due = self.current.as_ref().is_none_or(|it| {
+            it.bytes >= self.segment_bytes
+                || now.saturating_sub(it.started) >= self.segment_age.as_millis() as u64
+        });
+
+        if due {
+            self.rotate(now)?;
+        }
+
+        let current = self.current.as_mut().expect("A segment was just started");
+
+        let mut line = serde_json::to_vec(entry).context("Serializing audit log entry")?;
+        line.push(b'\n');
+
+        current
+            .file
+            .write_all(&line)
+            .with_context(|| format!("Writing to audit log segment {}", current.path.display()))?;
+        current.bytes += line.len() as u64;
+
+        Ok(())
+    }
+
+    /// Compress the current segment, and start a new one
+    fn rotate(&mut self, now: u64) -> Result<()> {
+        if let Some(current) = self.current.take() {
+            // Segments are named after when they started, which has to be after the last one
+            let now = now.max(current.started + 1);
+            drop(current.file);
+            compress(&current.path)?;
+
+            return self.start(now);
+        }
+
+        self.start(now)
+    }
+
+    fn start(&mut self, now: u64) -> Result<()> {
+        let path = self.dir.join(format!("events-{now}.jsonl"));
+        self.current = Some(Current::open(path, now)?);
+
+        self.prune(now)
+    }
+
+    /// Delete compressed segments which only have entries from before the retention
+    fn prune(&self, now: u64) -> Result<()> {
+        let cutoff = now.saturating_sub(self.retention.as_millis() as u64);
+        let segments = segments(&self.dir)?;
+
+        for pair in segments.windows(2) {
+            if pair[0].compressed && pair[1].started <= cutoff {
+                info!("Deleting audit log segment {}", pair[0].path.display());
+                std::fs::remove_file(&pair[0].path).with_context(|| {
+                    format!("Deleting audit log segment {}", pair[0].path.display())
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Current {
+    /// Open a segment to append to, first dropping a line which was cut off by a crash
+    fn open(path: PathBuf, started: u64) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Opening audit log segment {}", path.display()))?;
+
+        let len = file.metadata()?.len();
+        let bytes = complete_len(&mut file)
+            .with_context(|| format!("Reading audit log segment {}", path.display()))?;
+
+        if bytes < len {
+            warn!(
+                "Dropping {} bytes cut off from the end of audit log segment {}",
+                len - bytes,
+                path.display()
+            );
+            file.set_len(bytes)?;
+        }
+
+        Ok(Self {
+            path,
+            file,
+            started,
+            bytes,
+        })
+    }
+}
+
+/// Length of `file` up to and including its last newline
+fn complete_len(file: &mut File) -> std::io::Result<u64> {
+    let mut end = file.metadata()?.len();
+    let mut buffer = [0; 4096];
+
+    while end > 0 {
+        let start = end.saturating_sub(buffer.len() as u64);
+        let chunk = &mut buffer[..(end - start) as usize];
+
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+
+        if let Some(at) = chunk.iter().rposition(|it| *it == b'\n') {
+            return Ok(start + at as u64 + 1);
+        }
+
+        end = start;
+    }
+
+    Ok(0)
+}
+
+/// Replace a segment with a gzip compressed copy
+fn compress(path: &Path) -> Result<()> {
+    let compressed = path.with_extension("jsonl.gz");
+
+    let mut source = File::open(path)
+        .with_context(|| format!("Opening audit log segment {}", path.display()))?;
+    let mut encoder = GzEncoder::new(
+        File::create(&compressed)
+            .with_context(|| format!("Creating audit log segment {}", compressed.display()))?,
+        Compression::default(),
+    );
+
+    std::io::copy(&mut source, &mut encoder)
+        .and_then(|_| encoder.finish())
+        .and_then(|it| it.sync_all())
+        .with_context(|| format!("Compressing audit log segment {}", path.display()))?;
+
+    std::fs::remove_file(path)
+        .with_context(|| format!("Deleting audit log segment {}", path.display()))?;
+
+    Ok(())
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|it| it.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Write, time::Duration};
+
+    use data::events::{AlertRaised, Event, EventSubscription, GcEvent};
+    use tempdir::TempDir;
+
+    use super::{search, segments, Entry, Query, Writer};
+    use crate::{application::AuditConfig, bus::Filter};
+
+    fn gc(unixtime: u64, instance: &str) -> Entry {
+        Entry {
+            unixtime,
+            sequence: unixtime,
+            epoch: 1,
+            event: Event::GcEvent(GcEvent {
+                unixtime,
+                instance: instance.into(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn query(from: u64, to: u64, limit: usize) -> Query {
+        Query {
+            from,
+            to,
+            filter: Filter::new(&EventSubscription::default()),
+            limit,
+        }
+    }
+
+    fn unixtimes((entries, truncated): (Vec<Entry>, bool)) -> (Vec<u64>, bool) {
+        (entries.iter().map(|it| it.unixtime).collect(), truncated)
+    }
+
+    #[test]
+    fn rotates_compresses_and_searches() {
+        let dir = TempDir::new("audit").unwrap();
+        let config = AuditConfig {
+            enabled: true,
+            segment_bytes: u64::MAX,
+            segment_age: Duration::from_secs(10),
+            retention: Duration::from_secs(60),
+            ..Default::default()
+        };
+
+        // Ten seconds of entries to each segment
+        let mut writer = Writer::open(dir.path(), &config, 0).unwrap();
+        for unixtime in 0..30 {
+            let instance = if unixtime % 2 == 0 {
+                "survival"
+            } else {
+                "creative"
+            };
+            writer
+                .write(&gc(unixtime, instance), unixtime * 1000)
+                .unwrap();
+        }
+
+        let compressed = segments(dir.path())
+            .unwrap()
+            .iter()
+            .map(|it| (it.started, it.compressed))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            compressed,
+            [(0, true), (10_000, true), (20_000, false)],
+            "Only the latest segment is left uncompressed"
+        );
+
+        assert_eq!(
+            unixtimes(search(dir.path(), &query(8, 12, 100)).unwrap()),
+            (Vec::from([8, 9, 10, 11, 12]), false),
+            "Across segments"
+        );
+        assert_eq!(
+            unixtimes(search(dir.path(), &query(0, u64::MAX, 3)).unwrap()),
+            (Vec::from([27, 28, 29]), true),
+            "The latest are kept"
+        );
+
+        let mut subscription = EventSubscription::default();
+        subscription.instances.push("creative".into());
+        let mut creative = query(0, 6, 100);
+        creative.filter = Filter::new(&subscription);
+        assert_eq!(
+            unixtimes(search(dir.path(), &creative).unwrap()),
+            (Vec::from([1, 3, 5]), false)
+        );
+
+        // Reopening carries on with the latest segment
+        drop(writer);
+        let mut writer = Writer::open(dir.path(), &config, 29_500).unwrap();
+        writer.write(&gc(29, "survival"), 29_500).unwrap();
+        assert_eq!(segments(dir.path()).unwrap().len(), 3);
+
+        // Segments are deleted once everything in them is past the retention
+        writer.write(&gc(75, "survival"), 75_000).unwrap();
+        let started = segments(dir.path())
+            .unwrap()
+            .iter()
+            .map(|it| it.started)
+            .collect::<Vec<_>>();
+        assert_eq!(started, [10_000, 20_000, 75_000]);
+    }
+
+    #[test]
+    fn rotates_by_size_and_skips_cut_off_lines() {
+        let dir = TempDir::new("audit").unwrap();
+        let config = AuditConfig {
+            enabled: true,
+            segment_bytes: 1,
+            ..Default::default()
+        };
+
+        let alert = Entry {
+            unixtime: 5,
+            sequence: 1,
+            epoch: 1,
+            event: Event::AlertRaised(AlertRaised {
+                rule: "memory".into(),
+                ..Default::default()
+            }),
+        };
+
+        // Rotated within the same millisecond
+        let mut writer = Writer::open(dir.path(), &config, 1000).unwrap();
+        writer.write(&alert, 1000).unwrap();
+        writer.write(&alert, 1000).unwrap();
+        assert_eq!(segments(dir.path()).unwrap().len(), 2);
+
+        // As if the server crashed partway through a line
+        let latest = segments(dir.path()).unwrap().pop().unwrap();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&latest.path)
+            .unwrap()
+            .write_all(br#"{"unixtime": 6, "seq"#)
+            .unwrap();
+
+        let (entries, _) = search(dir.path(), &query(0, u64::MAX, 100)).unwrap();
+        assert_eq!(entries, [alert.clone(), alert.clone()]);
+
+        // Carrying on with the segment after the crash drops the cut off line
+        drop(writer);
+        let config = AuditConfig {
+            segment_bytes: u64::MAX,
+            ..config
+        };
+        let after = Entry {
+            unixtime: 7,
+            sequence: 1,
+            ..alert.clone()
+        };
+
+        let mut writer = Writer::open(dir.path(), &config, 2000).unwrap();
+        writer.write(&after, 2000).unwrap();
+        writer.write(&after, 2000).unwrap();
+        assert_eq!(segments(dir.path()).unwrap().len(), 2);
+
+        let (entries, _) = search(dir.path(), &query(0, u64::MAX, 100)).unwrap();
+        assert_eq!(
+            entries,
+            [alert.clone(), alert, after.clone(), after.clone()]
+        );
+
+        // Lines which cannot be read do not hide the ones after them
+        let latest = segments(dir.path()).unwrap().pop().unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&latest.path)
+            .unwrap();
+        let line = serde_json::to_string(&Entry {
+            unixtime: 8,
+            ..after
+        })
+        .unwrap();
+        writeln!(file, "garbage\n{line}").unwrap();
+
+        let (entries, _) = search(dir.path(), &query(8, u64::MAX, 100)).unwrap();
+        assert_eq!(entries.len(), 1);
+    }
+}
@@ -0,0 +1,121 @@
+//! Bearer token authentication for services which need to know who is calling
+
+use std::{collections::HashMap, sync::Arc};
+
+use tonic::{Request, Status};
+
+use crate::application::AuthConfig;
+
+/// A user who has been authenticated for a request
+///
+/// Inserted into the extensions of every request that passes through [`Authenticator`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub admin: bool,
+}
+
+impl User {
+    /// Fails unless the user may call admin services
+    pub fn require_admin(&self) -> Result<(), Status> {
+        if self.admin {
+            Ok(())
+        } else {
+            Err(Status::permission_denied(format!(
+                "{} is not an admin",
+                self.name
+            )))
+        }
+    }
+}
+
+/// Checks the `authorization: Bearer <token>` metadata of requests against the configured users
+#[derive(Debug, Clone)]
+pub struct Authenticator {
+    users: Arc<HashMap<String, User>>,
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> Self {
+        if config.users.is_empty() {
+            tracing::warn!("No users are configured, all authenticated requests will be rejected");
+        }
+
+        let users = config
+            .users
+            .iter()
+            .map(|it| {
+                (
+                    it.token.clone(),
+                    User {
+                        name: it.name.clone(),
+                        admin: it.admin,
+                    },
+                )
+            })
+            .collect();
+
+        Self {
+            users: Arc::new(users),
+        }
+    }
+
+    /// Interceptor which rejects requests without a known token
+    pub fn interceptor(
+        &self,
+    ) -> impl FnMut(Request<()>) -> Result<Request<()>, Status> + Clone + Send + 'static {
+        let users = self.users.clone();
+
+        move |request| {
+            if bearer(&request)?.is_none() {
+                return Err(Status::unauthenticated("Missing bearer token"));
+            }
+
+            authenticate(&users, request)
+        }
+    }
+
+    /// Interceptor which lets requests without a token through unauthenticated, and rejects
+    /// those with an unknown token
+    pub fn optional_interceptor(
+        &self,
+    ) -> impl FnMut(Request<()>) -> Result<Request<()>, Status> + Clone + Send + 'static {
+        let users = self.users.clone();
+
+        move |request| authenticate(&users, request)
+    }
+}
+
+fn bearer(request: &Request<()>) -> Result<Option<&str>, Status> {
+    let header = match request.metadata().get("authorization") {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+
+    header
+        .to_str()
+        .ok()
+        .and_then(|it| it.strip_prefix("Bearer "))
+        .map(Some)
+        .ok_or_else(|| Status::unauthenticated("Malformed bearer token"))
+}
+
+/// Insert the user for the request's token, if it has one
+fn authenticate(
+    users: &HashMap<String, User>,
+    mut request: Request<()>,
+) -> Result<Request<()>, Status> {
+    let token = match bearer(&request)? {
+        Some(token) => token,
+        None => return Ok(request),
+    };
+
+    let user = users
+        .get(token)
+        .cloned()
+        .ok_or_else(|| Status::unauthenticated("Unknown bearer token"))?;
+
+    request.extensions_mut().insert(user);
+
+    Ok(request)
+}
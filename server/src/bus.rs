@@ -0,0 +1,389 @@
+//! Bus that events from every part of the server are published on
+//!
+//! Producers hold a typed [`Publisher`] for each kind of event they make, and consumers such as
+//! the Events service take a [`BusSubscription`], which receives everything published after it
+//! was created. Every event is numbered in the order it was published, and the latest are kept so
+//! that a subscription can resume after the last event it received. Numbering starts over when the
+//! server restarts, so each bus also has an epoch which tells its numbering apart from earlier
+//! runs.
+
+use std::{
+    collections::VecDeque,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use data::events::{AlertCleared, AlertRaised, Event, GcEvent, ProcessSnapshot, SystemSnapshot};
+use futures::Stream;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+
+mod filter;
+
+pub use filter::Filter;
+
+/// Events which a subscription has not received yet are dropped past this many
+pub const CAPACITY: usize = 256;
+
+/// Events kept for subscriptions to resume from
+pub const REPLAY: usize = 1024;
+
+/// An event, along with where it was published on the bus
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sequenced {
+    /// Counts up from one for each event published since the server started
+    pub sequence: u64,
+    pub event: Event,
+}
+
+/// Events which a subscription missed, because it fell too far behind or resumed from further
+/// back than is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gap {
+    /// Sequence of the last event before the gap
+    pub after: u64,
+    /// Sequence of the first event after the gap
+    pub next: u64,
+}
+
+/// Kinds of event which can be published on the bus
+pub trait BusEvent {
+    fn into_event(self) -> Event;
+}
+
+macro_rules! bus_event {
+    ($($kind:ident),* $(,)?) => {
+        $(
+            impl BusEvent for $kind {
+                fn into_event(self) -> Event {
+                    Event::$kind(self)
+                }
+            }
+        )*
+    };
+}
+
+bus_event!(
+    SystemSnapshot,
+    ProcessSnapshot,
+    AlertRaised,
+    AlertCleared,
+    GcEvent
+);
+
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    sender: broadcast::Sender<Sequenced>,
+    /// Held while sending, so that events are received in the order they are numbered, and so
+    /// that subscriptions start exactly where the replay log ends
+    state: Mutex<State>,
+    replay: usize,
+    /// When the bus was created, in nanoseconds since the unix epoch
+    epoch: u64,
+}
+
+#[derive(Debug)]
+struct State {
+    /// The sequence of the latest event
+    latest: u64,
+    /// The latest events, oldest first
+    log: VecDeque<Sequenced>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(CAPACITY, REPLAY)
+    }
+}
+
+impl EventBus {
+    /// Create a bus which buffers up to `capacity` events for each subscription, and keeps the
+    /// latest `replay` events for subscriptions to resume from
+    pub fn new(capacity: usize, replay: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                sender: broadcast::channel(capacity).0,
+                state: Mutex::new(State {
+                    latest: 0,
+                    log: VecDeque::with_capacity(replay),
+                }),
+                replay,
+                epoch: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|it| it.as_nanos() as u64)
+                    .unwrap_or_default(),
+            }),
+        }
+    }
+
+    /// Identifies this run of the server, since sequences start over when it restarts
+    pub fn epoch(&self) -> u64 {
+        self.inner.epoch
+    }
+
+    /// Create a publisher for one kind of event
+    pub fn publisher<T: BusEvent>(&self) -> Publisher<T> {
+        Publisher {
+            bus: self.clone(),
+            _kind: PhantomData,
+        }
+    }
+
+    /// Receive every event published from now on
+    pub fn subscribe(&self) -> BusSubscription {
+        let state = self.inner.state.lock().unwrap();
+
+        BusSubscription {
+            replay: VecDeque::new(),
+            live: BroadcastStream::new(self.inner.sender.subscribe()),
+            last: state.latest,
+            epoch: self.inner.epoch,
+        }
+    }
+
+    /// Receive every event published after the one numbered `after` in `epoch`, followed by every
+    /// event published from now on
+    ///
+    /// Returns a gap first if some of those events are no longer kept. An `after` from another
+    /// epoch, or past the latest event, is from before a restart, and everything kept is missed.
+    pub fn subscribe_after(&self, epoch: u64, after: u64) -> (Option<Gap>, BusSubscription) {
+        let state = self.inner.state.lock().unwrap();
+
+        let restarted = epoch != self.inner.epoch || after > state.latest;
+        let from = if restarted { 0 } else { after };
+        let oldest = state.log.front().map_or(state.latest + 1, |it| it.sequence);
+
+        let gap = (restarted || oldest > from + 1).then_some(Gap {
+            after,
+            next: oldest,
+        });
+
+        let subscription = BusSubscription {
+            replay: state
+                .log
+                .iter()
+                .filter(|it| it.sequence > from)
+                .cloned()
+                .collect(),
+            live: BroadcastStream::new(self.inner.sender.subscribe()),
+            last: state.latest,
+            epoch: self.inner.epoch,
+        };
+
+        (gap, subscription)
+    }
+
+    /// Sequence of the latest event published, or zero if there has not been one
+    pub fn latest(&self) -> u64 {
+        self.inner.state.lock().unwrap().latest
+    }
+
+    fn publish(&self, event: Event) -> u64 {
+        let mut state = self.inner.state.lock().unwrap();
+        state.latest += 1;
+
+        let event = Sequenced {
+            sequence: state.latest,
+            event,
+        };
+
+        if self.inner.replay > 0 {
+            if state.log.len() == self.inner.replay {
+                state.log.pop_front();
+            }
+            state.log.push_back(event.clone());
+        }
+
+        // Nobody may be subscribed
+        let _ = self.inner.sender.send(event);
+
+        state.latest
+    }
+}
+
+/// Publishes one kind of event on an [`EventBus`]
+pub struct Publisher<T> {
+    bus: EventBus,
+    _kind: PhantomData<fn(T)>,
+}
+
+impl<T> Clone for Publisher<T> {
+    fn clone(&self) -> Self {
+        Self {
+            bus: self.bus.clone(),
+            _kind: PhantomData,
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for Publisher<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Publisher")
+            .field("kind", &std::any::type_name::<T>())
+            .finish()
+    }
+}
+
+impl<T: BusEvent> Publisher<T> {
+    /// Publish an event, returning its sequence
+    pub fn publish(&self, event: T) -> u64 {
+        self.bus.publish(event.into_event())
+    }
+}
+
+/// Stream of the events published on a bus, starting with any which are replayed
+///
+/// Yields a [`Gap`] in place of any events which were dropped because it fell too far behind,
+/// and carries on from the oldest event still buffered.
+pub struct BusSubscription {
+    replay: VecDeque<Sequenced>,
+    live: BroadcastStream<Sequenced>,
+    /// Sequence of the last live event, or of the latest event when subscribing
+    last: u64,
+    epoch: u64,
+}
+
+impl BusSubscription {
+    /// Epoch of the bus the events are from, see [`EventBus::epoch`]
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Sequence of the event just before the first one this will yield, which for a resumed
+    /// subscription is where its replay starts
+    pub fn start(&self) -> u64 {
+        self.replay
+            .front()
+            .map_or(self.last, |it| it.sequence.saturating_sub(1))
+    }
+}
+
+impl Stream for BusSubscription {
+    type Item = Result<Sequenced, Gap>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(event) = self.replay.pop_front() {
+            return Poll::Ready(Some(Ok(event)));
+        }
+
+        let polled = Pin::new(&mut self.live).poll_next(cx);
+
+        match polled {
+            Poll::Ready(Some(Ok(event))) => {
+                self.last = event.sequence;
+                Poll::Ready(Some(Ok(event)))
+            }
+            Poll::Ready(Some(Err(BroadcastStreamRecvError::Lagged(missed)))) => {
+                let gap = Gap {
+                    after: self.last,
+                    next: self.last + missed + 1,
+                };
+                self.last += missed;
+
+                Poll::Ready(Some(Err(gap)))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use data::events::{AlertRaised, Event, SystemSnapshot};
+
+    use super::{BusSubscription, EventBus, Gap};
+    use crate::prelude::*;
+
+    #[tokio::test]
+    async fn sequenced_and_lagged() {
+        let bus = EventBus::new(2, 0);
+        let systems = bus.publisher::<SystemSnapshot>();
+        let alerts = bus.publisher::<AlertRaised>();
+
+        let mut early = bus.subscribe();
+
+        assert_eq!(systems.publish(SystemSnapshot::default()), 1);
+        let mut late = bus.subscribe();
+        assert_eq!(alerts.publish(AlertRaised::default()), 2);
+        assert_eq!(bus.latest(), 2);
+
+        let first = early.next().await.unwrap().unwrap();
+        assert_eq!(first.sequence, 1);
+        assert!(matches!(first.event, Event::SystemSnapshot(_)));
+
+        let second = late.next().await.unwrap().unwrap();
+        assert_eq!(second.sequence, 2, "Only events after subscribing");
+        assert!(matches!(second.event, Event::AlertRaised(_)));
+
+        // Past the capacity, the oldest events are dropped for subscriptions which fell behind
+        for _ in 0..3 {
+            systems.publish(SystemSnapshot::default());
+        }
+
+        assert_eq!(late.next().await.unwrap(), Err(Gap { after: 2, next: 4 }));
+        assert_eq!(late.next().await.unwrap().unwrap().sequence, 4);
+        assert_eq!(late.next().await.unwrap().unwrap().sequence, 5);
+
+        assert_eq!(early.next().await.unwrap(), Err(Gap { after: 1, next: 4 }));
+        assert_eq!(early.next().await.unwrap().unwrap().sequence, 4);
+    }
+
+    #[tokio::test]
+    async fn resume_after() {
+        let bus = EventBus::new(8, 3);
+        let systems = bus.publisher::<SystemSnapshot>();
+
+        let sequences = |(gap, subscription): (Option<Gap>, BusSubscription)| {
+            let sequences = subscription
+                .replay
+                .iter()
+                .map(|it| it.sequence)
+                .collect::<Vec<_>>();
+
+            (gap, sequences)
+        };
+
+        let epoch = bus.epoch();
+        assert_eq!(sequences(bus.subscribe_after(epoch, 0)), (None, Vec::new()));
+
+        for _ in 0..5 {
+            systems.publish(SystemSnapshot::default());
+        }
+
+        assert_eq!(
+            sequences(bus.subscribe_after(epoch, 3)),
+            (None, Vec::from([4, 5]))
+        );
+        assert_eq!(sequences(bus.subscribe_after(epoch, 5)), (None, Vec::new()));
+        assert_eq!(
+            sequences(bus.subscribe_after(epoch, 1)),
+            (Some(Gap { after: 1, next: 3 }), Vec::from([3, 4, 5])),
+            "Only the latest three are kept"
+        );
+        assert_eq!(
+            sequences(bus.subscribe_after(epoch, 40)),
+            (Some(Gap { after: 40, next: 3 }), Vec::from([3, 4, 5])),
+            "From before a restart"
+        );
+        assert_eq!(
+            sequences(bus.subscribe_after(epoch + 1, 4)),
+            (Some(Gap { after: 4, next: 3 }), Vec::from([3, 4, 5])),
+            "From another run which got as far"
+        );
+
+        // Replayed events come before live ones
+        let (_, mut subscription) = bus.subscribe_after(epoch, 4);
+        systems.publish(SystemSnapshot::default());
+        assert_eq!(subscription.next().await.unwrap().unwrap().sequence, 5);
+        assert_eq!(subscription.next().await.unwrap().unwrap().sequence, 6);
+    }
+}
@@ -0,0 +1,175 @@
+//! Which events a subscriber asked for
+
+use data::events::{Event, EventSubscription, Severity, Topic};
+
+use crate::{
+    application::{EventSeverity, EventTopic},
+    information::{Subscription, SystemInfo},
+    util::Collectable,
+};
+
+/// Which events a subscription asked for
+pub struct Filter {
+    /// Every topic when empty
+    topics: Vec<Topic>,
+    /// Every instance when empty
+    instances: Vec<String>,
+    min_severity: Severity,
+}
+
+impl Filter {
+    /// Garbage collection pauses longer than a game tick are noticeable in game
+    const TICK_MS: f32 = 50.0;
+
+    /// Filter for events from the server config, rather than from a subscriber
+    pub fn from_config(
+        topics: &[EventTopic],
+        instances: &[String],
+        min_severity: EventSeverity,
+    ) -> Self {
+        let topic = |it: &EventTopic| match it {
+            EventTopic::System => Topic::System,
+            EventTopic::Process => Topic::Process,
+            EventTopic::Alert => Topic::Alert,
+            EventTopic::Gc => Topic::Gc,
+        };
+
+        Self {
+            topics: topics.iter().map(topic).collect(),
+            instances: instances.to_vec(),
+            min_severity: match min_severity {
+                EventSeverity::Debug => Severity::Debug,
+                EventSeverity::Info => Severity::Info,
+                EventSeverity::Warning => Severity::Warning,
+            },
+        }
+    }
+
+    pub fn new(subscription: &EventSubscription) -> Self {
+        Self {
+            topics: subscription
+                .topics()
+                .filter(|it| *it != Topic::Unknown)
+                .collect(),
+            instances: subscription.instances.clone(),
+            min_severity: subscription.min_severity(),
+        }
+    }
+
+    fn wants(&self, topic: Topic) -> bool {
+        self.topics.is_empty() || self.topics.contains(&topic)
+    }
+
+    /// Whether system or process snapshots can match
+    pub fn wants_samples(&self) -> bool {
+        self.min_severity <= Severity::Debug
+            && (self.wants(Topic::System) || self.wants(Topic::Process))
+    }
+
+    /// Keep `info` sampling while snapshots can match, for as long as the returned subscription is
+    /// held onto
+    ///
+    /// Snapshots are only taken while someone wants them.
+    pub fn sampling(&self, info: &SystemInfo) -> Option<Subscription> {
+        self.wants_samples().then(|| info.clone().collect())
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        let (topic, severity, instance) = match Self::describe(event) {
+            Some(it) => it,
+            // Every subscription has to know what it missed, and that it is still alive
+            None => return true,
+        };
+
+        let instance = match instance {
+            Some(instance) => {
+                self.instances.is_empty() || self.instances.iter().any(|it| it == instance)
+            }
+            None => true,
+        };
+
+        self.wants(topic) && severity >= self.min_severity && instance
+    }
+
+    /// Topic and severity of an event, along with the instance it is about
+    ///
+    /// None for events which are about the stream itself, rather than any topic.
+    pub fn describe(event: &Event) -> Option<(Topic, Severity, Option<&str>)> {
+        // Alerts on system wide metrics leave their instance empty
+        fn instance(instance: &str) -> Option<&str> {
+            Some(instance).filter(|it| !it.is_empty())
+        }
+
+        Some(match event {
+            Event::SystemSnapshot(_) => (Topic::System, Severity::Debug, None),
+            Event::ProcessSnapshot(it) => (Topic::Process, Severity::Debug, Some(&*it.instance)),
+            Event::AlertRaised(it) => (Topic::Alert, Severity::Warning, instance(&it.instance)),
+            Event::AlertCleared(it) => (Topic::Alert, Severity::Info, instance(&it.instance)),
+            Event::GcEvent(it) if it.pause_ms > Self::TICK_MS => {
+                (Topic::Gc, Severity::Warning, Some(&*it.instance))
+            }
+            Event::GcEvent(it) => (Topic::Gc, Severity::Info, Some(&*it.instance)),
+            Event::Gap(_) | Event::Heartbeat(_) | Event::Batch(_) => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use data::events::*;
+
+    use super::Filter;
+
+    #[test]
+    fn filters_topics_instances_and_severity() {
+        let process = |instance: &str| {
+            Event::ProcessSnapshot(ProcessSnapshot {
+                instance: instance.into(),
+                ..Default::default()
+            })
+        };
+        let gc = |instance: &str, pause_ms| {
+            Event::GcEvent(GcEvent {
+                instance: instance.into(),
+                pause_ms,
+                ..Default::default()
+            })
+        };
+        let alert = |instance: &str, subject: &str| {
+            Event::AlertRaised(AlertRaised {
+                subject: subject.into(),
+                instance: instance.into(),
+                ..Default::default()
+            })
+        };
+        let system = Event::SystemSnapshot(SystemSnapshot::default());
+
+        let everything = Filter::new(&EventSubscription::default());
+        assert!(everything.wants_samples());
+        assert!(everything.matches(&system));
+        assert!(everything.matches(&process("creative")));
+
+        let mut subscription = EventSubscription {
+            instances: Vec::from(["survival".into()]),
+            ..Default::default()
+        };
+        subscription.set_min_severity(Severity::Info);
+        subscription.topics.push(Topic::Gc.into());
+        subscription.topics.push(Topic::Alert.into());
+
+        let filter = Filter::new(&subscription);
+        assert!(!filter.wants_samples());
+        assert!(!filter.matches(&system));
+        assert!(!filter.matches(&process("survival")));
+        assert!(filter.matches(&gc("survival", 10.0)));
+        assert!(!filter.matches(&gc("creative", 10.0)));
+        assert!(filter.matches(&alert("survival", "survival")));
+        assert!(!filter.matches(&alert("creative", "creative")));
+        assert!(filter.matches(&alert("", "/srv")), "Not about an instance");
+
+        subscription.set_min_severity(Severity::Warning);
+        let filter = Filter::new(&subscription);
+        assert!(!filter.matches(&gc("survival", 10.0)));
+        assert!(filter.matches(&gc("survival", 200.0)));
+    }
+}
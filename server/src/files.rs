@@ -0,0 +1,443 @@
+//! Remote filesystem watches inside of managed instance directories
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use async_inotify::{
+    futures::DirectoryWatchEvent,
+    handle::{Handle, RequestError, WatchError, WatchRequest, WatchType},
+};
+use data::files::*;
+use futures::stream::{self, BoxStream};
+use glob::Pattern;
+use tonic::{Request, Status};
+use tracing::info;
+
+use crate::{application::Config, auth::User, prelude::*};
+
+/// Upper limit on the directories a single recursive watch may cover
+const MAX_RECURSIVE_WATCHES: usize = 256;
+
+pub struct FilesService {
+    pub config: Arc<Config>,
+    pub inotify: Handle,
+}
+
+#[tonic::async_trait]
+impl Files for FilesService {
+    type WatchStream = StreamDescriptor<FileEvent>;
+
+    async fn watch(&self, request: Request<WatchSubscription>) -> StreamResponse<FileEvent> {
+        let user = request
+            .extensions()
+            .get::<User>()
+            .map(|it| it.name.clone())
+            .unwrap_or_default();
+        let subscription = request.into_inner();
+
+        let instance = self
+            .config
+            .instances
+            .get(&subscription.instance)
+            .ok_or_else(|| {
+                Status::not_found(format!("No instance with id {}", subscription.instance))
+            })?;
+
+        let include = subscription
+            .include
+            .iter()
+            .map(|it| Pattern::new(it))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Status::invalid_argument(format!("Invalid include pattern: {e}")))?;
+
+        let follow = !subscription.no_follow_symlinks;
+        let (path, is_dir) = tokio::task::block_in_place(|| {
+            let path = resolve(&instance.root, Path::new(&subscription.path), follow)?;
+            let metadata = std::fs::symlink_metadata(&path).map_err(|e| {
+                Status::not_found(format!("Could not read {}: {e}", path.display()))
+            })?;
+
+            Ok::<_, Status>((path, metadata.is_dir()))
+        })?;
+
+        info!(
+            "Files Service Starting Watch on {} for {user}",
+            path.display()
+        );
+
+        let mut inotify = self.inotify.clone();
+        let kinds = Kinds::new(subscription.events());
+
+        let events = if is_dir {
+            if kinds.changed {
+                return Err(Status::invalid_argument(
+                    "CHANGED events can only be requested for a file",
+                ));
+            }
+
+            let dirs = if subscription.recursive {
+                tokio::task::block_in_place(|| walk(&path))?
+            } else {
+                Vec::from([path.clone()])
+            };
+
+            let mut watches = Vec::with_capacity(dirs.len());
+            for dir in dirs {
+                let prefix = dir.strip_prefix(&path).unwrap_or(&dir).to_owned();
+                let watch = kinds
+                    .apply(inotify.dir(dir).map_err(request_status)?)
+                    .watch()
+                    .await
+                    .map_err(watch_status)?;
+
+                watches.push(watch.map(move |it| relative(&prefix, it)));
+            }
+
+            stream::select_all(watches).boxed()
+        } else {
+            kinds
+                .apply(inotify.file(path).map_err(request_status)?)
+                .content_changed(kinds.changed)
+                .watch()
+                .await
+                .map_err(watch_status)?
+                .map(FileEvent::from)
+                .boxed()
+        };
+
+        Ok(filter(events, include).map(Ok).into_msg())
+    }
+}
+
+/// The kinds of events requested by a [`WatchSubscription`]
+struct Kinds {
+    read: bool,
+    write: bool,
+    open: bool,
+    close: bool,
+    changed: bool,
+}
+
+impl Kinds {
+    fn new(kinds: impl Iterator<Item = FileEventKind>) -> Self {
+        let mut it = Self {
+            read: false,
+            write: false,
+            open: false,
+            close: false,
+            changed: false,
+        };
+
+        let mut any = false;
+        for kind in kinds {
+            any = true;
+            match kind {
+                FileEventKind::Unknown => {}
+                FileEventKind::Read => it.read = true,
+                FileEventKind::Write => it.write = true,
+                FileEventKind::Open => it.open = true,
+                FileEventKind::Close => it.close = true,
+                FileEventKind::Changed => it.changed = true,
+            }
+        }
+
+        if !any {
+            it.read = true;
+            it.write = true;
+            it.open = true;
+            it.close = true;
+        }
+
+        it
+    }
+
+    fn apply<'handle, T: WatchType>(
+        &self,
+        request: WatchRequest<'handle, T>,
+    ) -> WatchRequest<'handle, T> {
+        request
+            .read(self.read)
+            .modify(self.write)
+            .open(self.open)
+            .close(self.close)
+            // Paths are resolved before they are checked, so the kernel must not follow a link
+            // swapped in since then
+            .follow_symlinks(false)
+    }
+}
+
+/// Resolve `path` inside of `root` to the real path it refers to, rejecting anything which would
+/// escape it
+///
+/// When not following, a `path` which is itself a symlink is rejected.
+fn resolve(root: &Path, path: &Path, follow: bool) -> Result<PathBuf, Status> {
+    let root = root
+        .canonicalize()
+        .map_err(|e| Status::failed_precondition(format!("Instance root is unavailable: {e}")))?;
+
+    if path.is_absolute() {
+        return Err(Status::invalid_argument(
+            "Path must be relative to the instance",
+        ));
+    }
+
+    let joined = root.join(path);
+    let not_found = || Status::not_found(format!("Nothing exists at {}", path.display()));
+
+    if !follow {
+        let metadata = std::fs::symlink_metadata(&joined).map_err(|_| not_found())?;
+
+        if metadata.file_type().is_symlink() {
+            return Err(Status::invalid_argument(format!(
+                "{} is a symlink, and symlinks are not followed",
+                path.display()
+            )));
+        }
+    }
+
+    let resolved = joined.canonicalize().map_err(|_| not_found())?;
+
+    if !resolved.starts_with(&root) {
+        return Err(Status::permission_denied(format!(
+            "{} is outside of the instance",
+            path.display()
+        )));
+    }
+
+    Ok(resolved)
+}
+
+/// Find `path` and every directory below it, skipping symlinks so that none lead out of it
+///
+/// `path` must already be resolved, as by [`resolve`].
+fn walk(path: &Path) -> Result<Vec<PathBuf>, Status> {
+    let mut found = Vec::from([path.to_owned()]);
+    let mut next = 0;
+
+    while next < found.len() {
+        let entries = std::fs::read_dir(&found[next])
+            .map_err(|e| Status::internal(format!("Could not read directory: {e}")))?;
+        next += 1;
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+
+            if std::fs::symlink_metadata(&path).is_ok_and(|it| it.is_dir()) {
+                found.push(path);
+            }
+        }
+
+        if found.len() > MAX_RECURSIVE_WATCHES {
+            return Err(Status::resource_exhausted(format!(
+                "Recursive watches may cover at most {MAX_RECURSIVE_WATCHES} directories"
+            )));
+        }
+    }
+
+    Ok(found)
+}
+
+/// Convert an event from a watch on a sub directory to be relative to the watched path
+fn relative(prefix: &Path, event: DirectoryWatchEvent) -> FileEvent {
+    let inner_path = event
+        .inner_path
+        .as_ref()
+        .map(|it| prefix.join(it).to_string_lossy().into_owned());
+
+    DirectoryWatchEvent {
+        inner_path,
+        ..event
+    }
+    .into()
+}
+
+fn filter(
+    events: BoxStream<'static, FileEvent>,
+    include: Vec<Pattern>,
+) -> BoxStream<'static, FileEvent> {
+    if include.is_empty() {
+        return events;
+    }
+
+    events
+        .filter(move |it| {
+            let matches = it.inner_path.is_empty()
+                || include
+                    .iter()
+                    .any(|pattern| pattern.matches(&it.inner_path));
+
+            std::future::ready(matches)
+        })
+        .boxed()
+}
+
+fn request_status(e: RequestError) -> Status {
+    match e {
+        RequestError::DoesNotExist(_) => Status::not_found(e.to_string()),
+        RequestError::IncorrectType(_) => Status::invalid_argument(e.to_string()),
+    }
+}
+
+fn watch_status(e: WatchError) -> Status {
+    match e {
+        WatchError::WatcherShutdown => Status::unavailable(e.to_string()),
+        WatchError::Request(e) => request_status(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::Path, sync::Arc, time::Duration};
+
+    use async_inotify::handle::Handle;
+    use data::files::{Files, WatchSubscription};
+    use tempdir::TempDir;
+    use tonic::{Code, Request};
+
+    use super::{resolve, FilesService};
+    use crate::{
+        application::{Config, InstanceConfig},
+        prelude::*,
+    };
+
+    #[test]
+    fn resolve_stays_inside_root() {
+        let outside = TempDir::new("outside").unwrap();
+        let root = TempDir::new("root").unwrap();
+        let root_path = root.path().canonicalize().unwrap();
+
+        std::fs::create_dir(root.path().join("world")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("escape")).unwrap();
+
+        let check = |path: &str, follow: bool| resolve(root.path(), Path::new(path), follow);
+
+        assert_eq!(check("", true).unwrap(), root_path);
+        assert_eq!(check("world", true).unwrap(), root_path.join("world"));
+        assert_eq!(
+            check("world/../world", true).unwrap(),
+            root_path.join("world")
+        );
+
+        assert_eq!(
+            check("..", true).unwrap_err().code(),
+            Code::PermissionDenied
+        );
+        assert_eq!(
+            check("world/../..", false).unwrap_err().code(),
+            Code::PermissionDenied
+        );
+        assert_eq!(
+            check("escape", true).unwrap_err().code(),
+            Code::PermissionDenied
+        );
+        assert_eq!(
+            check(&outside.path().display().to_string(), true)
+                .unwrap_err()
+                .code(),
+            Code::InvalidArgument
+        );
+
+        assert_eq!(
+            check("escape", false).unwrap_err().code(),
+            Code::InvalidArgument,
+            "Links are refused when not following"
+        );
+        assert_eq!(check("world", false).unwrap(), root_path.join("world"));
+    }
+
+    fn service(root: &Path, inotify: &Handle) -> FilesService {
+        let mut config = Config::default();
+        config.instances.insert(
+            "survival".into(),
+            InstanceConfig {
+                root: root.to_owned(),
+                gc_log: "logs/gc.log".into(),
+                launch: None,
+            },
+        );
+
+        FilesService {
+            config: Arc::new(config),
+            inotify: inotify.clone(),
+        }
+    }
+
+    fn subscription(path: &str) -> WatchSubscription {
+        WatchSubscription {
+            instance: "survival".into(),
+            path: path.into(),
+            recursive: true,
+            no_follow_symlinks: true,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn recursive_watch_skips_symlinks() {
+        let outside = TempDir::new("outside").unwrap();
+        let root = TempDir::new("root").unwrap();
+
+        std::fs::create_dir(outside.path().join("sub")).unwrap();
+        std::fs::create_dir(root.path().join("world")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("escape")).unwrap();
+
+        let inotify = async_inotify::new().unwrap();
+        let service = service(root.path(), &inotify);
+
+        let through = service.watch(Request::new(subscription("escape"))).await;
+        assert_eq!(through.err().unwrap().code(), Code::InvalidArgument);
+
+        let mut events = service
+            .watch(Request::new(subscription("")))
+            .await
+            .unwrap()
+            .into_inner();
+
+        // Watches are started by the watcher task
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        std::fs::write(outside.path().join("sub/outside"), "outside").unwrap();
+        std::fs::write(outside.path().join("outside"), "outside").unwrap();
+        std::fs::write(root.path().join("world/inside"), "inside").unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.inner_path, "world/inside", "Nothing from outside");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn recursive_watch_of_many_directories() {
+        let root = TempDir::new("root").unwrap();
+
+        // More directories than the watcher task has room for requests at once
+        for region in 0..200 {
+            std::fs::create_dir_all(root.path().join(format!("world/region/{region}"))).unwrap();
+        }
+
+        let inotify = async_inotify::new().unwrap();
+        let service = service(root.path(), &inotify);
+
+        let mut events = service
+            .watch(Request::new(subscription("")))
+            .await
+            .unwrap()
+            .into_inner();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(inotify.stats().watches, 203);
+
+        std::fs::write(root.path().join("world/region/199/r.0.0.mca"), "chunks").unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.inner_path, "world/region/199/r.0.0.mca");
+    }
+}
@@ -0,0 +1,242 @@
+//! The standard `grpc.health.v1.Health` service, for load balancers and watchdogs
+//!
+//! Statuses are checked every [`CHECK_INTERVAL`] and reported for:
+//!
+//! - `""`, the server as a whole, which is serving while the collector and watcher are running
+//! - each gRPC service, by its full name, such as `event.Events`
+//! - `sysinfo` and `inotify`, the collector and file watcher tasks. The collector is only serving
+//!   while it keeps taking samples, so one which is stuck is reported as well as one which stopped
+//! - `instance/<id>` for each managed instance, which is serving while its server process is
+//!   running. Unknown when processes are not collected, or the collector is not serving.
+
+use std::time::Duration;
+
+use async_inotify::handle::Handle;
+use tokio::time::Instant;
+use tonic_health::{
+    proto::health_server::{Health, HealthServer},
+    server::{health_reporter, HealthReporter},
+    ServingStatus,
+};
+
+use crate::{
+    application::{Config, MetricGroup},
+    information::{self, Sample, SystemInfo},
+};
+
+/// Time between checks of every status
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The collector is stuck once it has gone this many sampling intervals, or checks if those are
+/// longer, without taking a sample
+const STUCK_AFTER: u32 = 3;
+
+/// Start checking statuses, returning the service to serve them from
+pub fn start_health(
+    config: &Config,
+    sysinfo: &SystemInfo,
+    inotify: &Handle,
+) -> HealthServer<impl Health> {
+    let (reporter, service) = health_reporter();
+
+    let mut instances = config.instances.keys().cloned().collect::<Vec<_>>();
+    instances.sort();
+
+    let checks = Checks {
+        sysinfo: sysinfo.clone(),
+        inotify: inotify.clone(),
+        instances,
+        processes: config.sysinfo.metrics.contains(&MetricGroup::Processes),
+        progress: (sysinfo.samples(), Instant::now()),
+    };
+
+    tokio::task::Builder::new()
+        .name("Health checks")
+        .spawn(checks.run(reporter));
+
+    service
+}
+
+struct Checks {
+    sysinfo: SystemInfo,
+    inotify: Handle,
+    /// Ids of the managed instances
+    instances: Vec<String>,
+    /// Whether instance processes are collected
+    processes: bool,
+    /// Samples taken as of the latest check which saw the count go up, and when that was
+    progress: (u64, Instant),
+}
+
+impl Checks {
+    async fn run(mut self, mut reporter: HealthReporter) {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            // Samples when the latest has gone stale, so that an idle collector is checked too
+            let sample = if self.sysinfo.is_running() {
+                Some(self.sysinfo.fresh().await)
+            } else {
+                None
+            };
+
+            // A sample which is too old, such as the empty one from before the first, says
+            // nothing about whether instances are running now
+            let sysinfo = self.progressing();
+            let recent = information::unixtime().saturating_sub(self.stuck_after().as_secs());
+            let sample =
+                sample.filter(|it| sysinfo && self.processes && it.system.unixtime >= recent);
+
+            let statuses = self.statuses(sysinfo, self.inotify.is_running(), sample.as_ref());
+
+            for (service, status) in statuses {
+                reporter.set_service_status(service, status).await;
+            }
+        }
+    }
+
+    /// Whether the collector is running and has taken a sample recently enough
+    fn progressing(&mut self) -> bool {
+        let samples = self.sysinfo.samples();
+        let now = Instant::now();
+
+        if samples != self.progress.0 {
+            self.progress = (samples, now);
+        }
+
+        self.sysinfo.is_running() && now.duration_since(self.progress.1) < self.stuck_after()
+    }
+
+    fn stuck_after(&self) -> Duration {
+        self.sysinfo.interval().max(CHECK_INTERVAL) * STUCK_AFTER
+    }
+
+    fn statuses(
+        &self,
+        sysinfo: bool,
+        inotify: bool,
+        sample: Option<&Sample>,
+    ) -> Vec<(String, ServingStatus)> {
+        let serving = |it: bool| {
+            if it {
+                ServingStatus::Serving
+            } else {
+                ServingStatus::NotServing
+            }
+        };
+
+        let mut statuses = Vec::from([
+            (String::new(), serving(sysinfo && inotify)),
+            ("sysinfo".into(), serving(sysinfo)),
+            ("inotify".into(), serving(inotify)),
+            ("event.Events".into(), serving(sysinfo)),
+            ("event.EventsAdmin".into(), ServingStatus::Serving),
+            ("files.Files".into(), serving(inotify)),
+            ("helloworld.HelloWorldService".into(), serving(sysinfo)),
+        ]);
+
+        for instance in &self.instances {
+            let running = sample.map(|sample| {
+                sample
+                    .processes
+                    .iter()
+                    .any(|it| it.instance == *instance && it.running)
+            });
+
+            let status = match running {
+                Some(running) => serving(running),
+                None => ServingStatus::Unknown,
+            };
+
+            statuses.push((format!("instance/{instance}"), status));
+        }
+
+        statuses
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use data::events::ProcessSnapshot;
+    use tokio::time::Instant;
+    use tonic_health::ServingStatus;
+
+    use super::{Checks, CHECK_INTERVAL, STUCK_AFTER};
+    use crate::{
+        application::Config,
+        information::{self, Sample, Sampler},
+    };
+
+    #[tokio::test]
+    async fn reports_each_status() {
+        let mut config = Config::default();
+        config.sysinfo.history.enabled = false;
+
+        let inotify = async_inotify::new().unwrap();
+        let mut checks = Checks {
+            sysinfo: information::start_sysinfo(&config, Sampler::new()),
+            inotify: inotify.clone(),
+            instances: Vec::from(["creative".into(), "survival".into()]),
+            processes: true,
+            progress: (0, Instant::now()),
+        };
+
+        let sample = Sample {
+            processes: Vec::from([ProcessSnapshot {
+                instance: "survival".into(),
+                running: true,
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+
+        let status = |statuses: &[(String, ServingStatus)], service: &str| {
+            statuses
+                .iter()
+                .find(|(name, _)| name == service)
+                .map(|(_, status)| *status)
+        };
+
+        let statuses = checks.statuses(true, true, Some(&sample));
+        assert_eq!(status(&statuses, ""), Some(ServingStatus::Serving));
+        assert_eq!(
+            status(&statuses, "instance/survival"),
+            Some(ServingStatus::Serving)
+        );
+        assert_eq!(
+            status(&statuses, "instance/creative"),
+            Some(ServingStatus::NotServing)
+        );
+
+        let statuses = checks.statuses(true, false, None);
+        assert_eq!(status(&statuses, ""), Some(ServingStatus::NotServing));
+        assert_eq!(
+            status(&statuses, "files.Files"),
+            Some(ServingStatus::NotServing)
+        );
+        assert_eq!(
+            status(&statuses, "event.Events"),
+            Some(ServingStatus::Serving)
+        );
+        assert_eq!(
+            status(&statuses, "instance/survival"),
+            Some(ServingStatus::Unknown),
+            "Without a sample"
+        );
+
+        assert!(checks.sysinfo.is_running());
+        assert!(checks.inotify.is_running());
+
+        // Nothing has been sampled, since nobody asked for it
+        assert!(checks.progressing());
+        tokio::time::pause();
+        tokio::time::advance(CHECK_INTERVAL * STUCK_AFTER).await;
+        assert!(!checks.progressing(), "Stuck without a sample");
+
+        tokio::time::resume();
+        checks.sysinfo.fresh().await;
+        assert!(checks.progressing());
+    }
+}
@@ -1,69 +1,654 @@
 //! Runtime Information Gathering
 
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 
-use sysinfo::{RefreshKind, System, SystemExt};
+use futures::{Stream, StreamExt};
+use sysinfo::{
+    DiskExt, NetworkExt, NetworksExt, ProcessRefreshKind, ProcessorExt, RefreshKind, System,
+    SystemExt,
+};
 use tokio::{
     select,
-    sync::watch::{channel, Receiver, Sender},
-    time::sleep,
+    sync::{
+        watch::{self, channel, Receiver, Ref, Sender},
+        Notify,
+    },
+    time::{self, sleep},
+};
+use tokio_stream::wrappers::WatchStream;
+use tracing::{debug, info, trace, warn};
+
+use data::events::{DiskUsage, LoadAverage, NetworkUsage, ProcessSnapshot, SystemSnapshot};
+
+use crate::{
+    application::{Config, InstanceConfig, MetricGroup},
+    bus::EventBus,
+    util::Collectable,
 };
-use tracing::{debug, info, trace};
 
-use data::events::SystemSnapshot;
-pub type SystemInfo = Receiver<SystemSnapshot>;
+mod alerts;
+mod cgroups;
+mod gc;
+mod history;
+mod processes;
+mod source;
+mod store;
+
+pub use alerts::{start_alerts, Alerts};
+use cgroups::Cgroups;
+pub use gc::{gc_log_flag, start_gc};
+use history::History;
+pub use history::Resolution;
+#[cfg(test)]
+pub use source::{sample, Scripted};
+pub use source::{MetricSource, Sampler};
+use store::Store;
+
+/// Everything collected at one point in time
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sample {
+    pub system: SystemSnapshot,
+    /// One for each managed instance, when the processes metric group is collected
+    pub processes: Vec<ProcessSnapshot>,
+}
+
+/// Shortest interval that may be set at runtime, so that clients can not keep the collector busy
+pub const MIN_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Handle to the system information collector, which allows you to check the current values and
+/// control how often they are sampled
+///
+/// The collector only samples while there is at least one subscription from [`collect`], and
+/// samples immediately when a subscription arrives after the latest snapshot has gone stale.
+///
+/// History and alert rules hold subscriptions of their own, so sampling never stops while either
+/// is in use.
+///
+/// [`collect`]: Collectable::collect
+#[derive(Debug, Clone)]
+pub struct SystemInfo {
+    snapshots: Receiver<Sample>,
+    control: Arc<Control>,
+    history: Option<Arc<Mutex<History>>>,
+}
+
+#[derive(Debug)]
+struct Control {
+    interval: watch::Sender<Duration>,
+    sample_now: Notify,
+    subscribers: AtomicUsize,
+    sampled_at: Mutex<Option<time::Instant>>,
+    samples: AtomicU64,
+}
+
+impl Control {
+    fn is_stale(&self) -> bool {
+        let interval = *self.interval.borrow();
+
+        match *self.sampled_at.lock().unwrap() {
+            Some(sampled_at) => sampled_at.elapsed() >= interval,
+            None => true,
+        }
+    }
+}
+
+impl SystemInfo {
+    /// The latest snapshot, which may be stale while nobody is subscribed
+    pub fn borrow(&self) -> Ref<'_, Sample> {
+        self.snapshots.borrow()
+    }
 
-/// Start a task to update a [`SystemSnapshot`] and a receiver that allows you to check the current
-/// values
-pub fn start_sysinfo() -> SystemInfo {
-    let (tx, rx) = channel(SystemSnapshot::default());
+    /// The latest snapshot, sampling a new one first if it has gone stale
+    pub async fn fresh(&self) -> Sample {
+        if self.control.is_stale() {
+            let mut snapshots = self.snapshots.clone();
+            self.control.sample_now.notify_one();
+
+            if tokio::time::timeout(self.interval(), snapshots.changed())
+                .await
+                .is_err()
+            {
+                debug!("Timed out waiting for a fresh snapshot");
+            }
+        }
+
+        self.snapshots.borrow().clone()
+    }
+
+    /// Whether the collector task is still running
+    pub fn is_running(&self) -> bool {
+        // The collector holds the only receiver of the interval
+        !self.control.interval.is_closed()
+    }
+
+    /// Subscriptions keeping the collector running, including those held by history and alerts
+    pub fn subscriptions(&self) -> usize {
+        self.control.subscribers.load(Ordering::SeqCst)
+    }
+
+    /// Samples taken since the collector started
+    pub fn samples(&self) -> u64 {
+        self.control.samples.load(Ordering::Relaxed)
+    }
+
+    pub fn interval(&self) -> Duration {
+        *self.control.interval.borrow()
+    }
+
+    /// Change the time between samples, clamped to at least [`MIN_INTERVAL`]
+    ///
+    /// Returns the interval that was applied
+    pub fn set_interval(&self, interval: Duration) -> Duration {
+        let interval = interval.max(MIN_INTERVAL);
+
+        info!("Setting sysinfo interval to {interval:?}");
+        let _ = self.control.interval.send(interval);
+
+        interval
+    }
+
+    /// Up to `limit` of the samples taken from `from` to `to` inclusive, or `None` if history is
+    /// not being kept
+    ///
+    /// See [`History::range`] for how the resolution is picked when none is given
+    pub fn history(
+        &self,
+        from: u64,
+        to: u64,
+        resolution: Option<Resolution>,
+        limit: usize,
+    ) -> Option<(Resolution, Vec<Sample>)> {
+        let history = self.history.as_ref()?;
+
+        Some(history.lock().unwrap().range(from, to, resolution, limit))
+    }
+}
+
+impl Collectable<Sample> for SystemInfo {
+    type Output = Subscription;
+
+    fn collect(self) -> Self::Output {
+        let previous = self.control.subscribers.fetch_add(1, Ordering::SeqCst);
+
+        if previous == 0 {
+            // Wake the collector from idling, which samples right away if the latest is stale
+            self.control.sample_now.notify_one();
+        }
+
+        Subscription {
+            stream: Samples::new(self.snapshots),
+            control: self.control,
+        }
+    }
+}
+
+/// Stream of the samples which have been taken
+///
+/// The channel starts out with an empty sample, before anything has been collected, which is
+/// skipped.
+struct Samples(WatchStream<Sample>);
+
+impl Samples {
+    fn new(snapshots: Receiver<Sample>) -> Self {
+        Self(WatchStream::new(snapshots))
+    }
+}
+
+impl Stream for Samples {
+    type Item = Sample;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match std::task::ready!(Pin::new(&mut self.0).poll_next(cx)) {
+                Some(sample) if sample.system.unixtime == 0 => continue,
+                sample => return Poll::Ready(sample),
+            }
+        }
+    }
+}
+
+/// Stream of snapshots, which keeps the collector running until dropped
+pub struct Subscription {
+    stream: Samples,
+    control: Arc<Control>,
+}
+
+impl Stream for Subscription {
+    type Item = Sample;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.control.subscribers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Start a task to update a [`Sample`] from `sampler` and a handle that allows you to check the
+/// current values
+///
+/// When history is persisted, what was saved before is loaded from the data directory first.
+pub fn start_sysinfo(config: &Config, sampler: Sampler) -> SystemInfo {
+    let history_dir = config.data_dir.join("history");
+    let config = &config.sysinfo;
+
+    let (tx, rx) = channel(Sample::default());
+    let (interval, interval_rx) = channel(config.interval.max(MIN_INTERVAL));
+
+    let control = Arc::new(Control {
+        interval,
+        sample_now: Notify::new(),
+        subscribers: AtomicUsize::new(0),
+        sampled_at: Mutex::new(None),
+        samples: AtomicU64::new(0),
+    });
 
     tokio::task::Builder::new()
         .name("Sysinfo watch")
-        .spawn(system_information_task(tx));
+        .spawn(system_information_task(
+            tx,
+            interval_rx,
+            control.clone(),
+            sampler,
+        ));
+
+    let mut info = SystemInfo {
+        snapshots: rx,
+        control,
+        history: None,
+    };
+
+    if config.history.enabled {
+        let mut history = History::new(config.history.retention);
+
+        let store = if config.history.persist {
+            Store::open(&history_dir, &mut history, unixtime())
+                .map_err(|e| warn!("History will not be saved: {e:#}"))
+                .ok()
+        } else {
+            None
+        };
+
+        let history = Arc::new(Mutex::new(history));
+
+        tokio::task::Builder::new()
+            .name("Sysinfo history")
+            .spawn(record_history(
+                info.clone().collect(),
+                history.clone(),
+                store,
+            ));
+
+        info.history = Some(history);
+    }
 
-    rx // .instrument(info_span!("Checking System Info"));
+    info
 }
 
-async fn system_information_task(tx: Sender<SystemSnapshot>) {
-    debug!("Setting up Sysinfo");
+/// Start a task which publishes the snapshots of every sample on the event bus
+///
+/// This does not keep the collector running by itself, it passes on samples taken for anyone
+/// else, such as subscribers of the Events service or history.
+pub fn publish_samples(info: &SystemInfo, bus: &EventBus) {
+    let mut samples = Samples::new(info.snapshots.clone());
+    let systems = bus.publisher::<SystemSnapshot>();
+    let processes = bus.publisher::<ProcessSnapshot>();
 
-    let mut system = System::new_with_specifics(RefreshKind::new().with_cpu().with_memory());
+    tokio::task::Builder::new()
+        .name("Sysinfo events")
+        .spawn(async move {
+            while let Some(sample) = samples.next().await {
+                systems.publish(sample.system);
+                for process in sample.processes {
+                    processes.publish(process);
+                }
+            }
+        });
+}
+
+async fn record_history(
+    mut samples: Subscription,
+    history: Arc<Mutex<History>>,
+    mut store: Option<Store>,
+) {
+    while let Some(sample) = samples.next().await {
+        let now = sample.system.unixtime;
+
+        let store = match store.as_mut() {
+            Some(store) => store,
+            None => {
+                history.lock().unwrap().push(sample);
+                continue;
+            }
+        };
+
+        let saved = tokio::task::block_in_place(|| {
+            store.append(Resolution::Raw, &sample)?;
+
+            // Queries wait on the lock, so what is written is copied out rather than held onto
+            let (minute, compact) = {
+                let mut history = history.lock().unwrap();
+                let minute = history.push(sample).cloned();
+                let compact = store.compaction_due(now).then(|| history.clone());
+
+                (minute, compact)
+            };
+
+            if let Some(minute) = minute {
+                store.append(Resolution::Minute, &minute)?;
+            }
 
+            match compact {
+                Some(history) => store.compact(&history),
+                None => Ok(()),
+            }
+        });
+
+        if let Err(e) = saved {
+            warn!("Could not save history: {e:#}");
+        }
+    }
+}
+
+/// Seconds since the unix epoch
+pub fn unixtime() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Unexpected Time from Before Unix Epoch")
+        .as_secs()
+}
+
+async fn system_information_task(
+    tx: Sender<Sample>,
+    mut interval: Receiver<Duration>,
+    control: Arc<Control>,
+    mut sampler: Sampler,
+) {
     trace!("Sysinfo Loop Starting");
     loop {
+        let period = *interval.borrow_and_update();
+        let idle = control.subscribers.load(Ordering::SeqCst) == 0;
+
         select! {
             _ = tx.closed() => {
                 info!("Last Receiver Closed for sysinfo before calculating, stopping");
                 break;
             },
-            _ = sleep(Duration::from_secs(3)) => {},
+            _ = control.sample_now.notified() => {
+                if !control.is_stale() {
+                    continue;
+                }
+
+                debug!("Sampling early");
+            },
+            _ = interval.changed() => continue,
+            _ = sleep(period), if !idle => {},
         }
 
-        let unixtime = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .expect("Unexpected Time from Before Unix Epoch")
-            .as_secs();
+        let (returned, sample) = tokio::task::spawn_blocking(move || {
+            let sample = sampler.sample();
+            (sampler, sample)
+        })
+        .await
+        .expect("Sampling panicked");
+        sampler = returned;
 
-        tokio::task::block_in_place(|| {
-            system.refresh_all();
-        });
+        debug!("Sysinfo sample: {:#?}", sample);
+
+        *control.sampled_at.lock().unwrap() = Some(time::Instant::now());
+        control.samples.fetch_add(1, Ordering::Relaxed);
+
+        if tx.send(sample).is_err() {
+            info!("Last Receiver Closed for sysinfo while calculating, stopping");
+            break;
+        }
+    }
+}
 
-        //let cpu_pressure: f32 = system.processors().iter().map(|it| it.cpu_usage()).sum();
-        let cpu_pressure = system.load_average().one as _;
-        let mem_pressure: f32 = system.used_memory() as f32 / system.total_memory() as f32;
+/// Source of host and process metrics from sysinfo, for the metric groups it was created with
+struct Collector {
+    system: System,
+    refresh: RefreshKind,
+    cpu: bool,
+    memory: bool,
+    pressure: bool,
+    cgroups: Option<Cgroups>,
+    instances: Vec<processes::Instance>,
+    refreshed_at: Instant,
+}
 
-        let snapshot = SystemSnapshot {
-            unixtime,
-            cpu_pressure,
-            mem_pressure,
+impl Collector {
+    /// sysinfo reports memory in KB
+    const KB: u64 = 1024;
+
+    fn new(metrics: &[MetricGroup], instances: &HashMap<String, InstanceConfig>) -> Self {
+        debug!("Setting up Sysinfo");
+
+        let cpu = metrics.contains(&MetricGroup::Cpu);
+        let memory = metrics.contains(&MetricGroup::Memory);
+        let disks = metrics.contains(&MetricGroup::Disks);
+        let networks = metrics.contains(&MetricGroup::Networks);
+        let processes = metrics.contains(&MetricGroup::Processes);
+        let pressure = metrics.contains(&MetricGroup::Pressure);
+
+        let cgroups = if metrics.contains(&MetricGroup::Cgroups) {
+            let cgroups = Cgroups::detect();
+            if cgroups.is_none() {
+                info!("There is no cgroup v2 hierarchy with controllers, cgroups will not be reported");
+            }
+
+            cgroups
+        } else {
+            None
         };
 
-        debug!("Sysinfo snapshot: {:#?}", snapshot);
+        let mut refresh = RefreshKind::new();
+        let mut initial = RefreshKind::new();
+        if cpu {
+            refresh = refresh.with_cpu();
+            initial = initial.with_cpu();
+        }
+        if memory {
+            refresh = refresh.with_memory();
+            initial = initial.with_memory();
+        }
+        if disks {
+            refresh = refresh.with_disks();
+            initial = initial.with_disks_list();
+        }
+        if networks {
+            refresh = refresh.with_networks();
+            initial = initial.with_networks_list();
+        }
+        if processes {
+            let kind = ProcessRefreshKind::new().with_cpu().with_disk_usage();
+            refresh = refresh.with_processes(kind);
+            initial = initial.with_processes(kind);
+        }
 
-        if tx.send(snapshot).is_err() {
-            info!("Last Receiver Closed for sysinfo while calculating, stopping");
-            break;
+        let instances = if processes {
+            instances
+                .iter()
+                .map(|(id, config)| processes::Instance::new(id, config))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        Self {
+            system: System::new_with_specifics(initial),
+            refresh,
+            cpu,
+            memory,
+            pressure,
+            cgroups,
+            instances,
+            refreshed_at: Instant::now(),
+        }
+    }
+}
+
+impl MetricSource for Collector {
+    fn sample(&mut self, sample: &mut Sample) {
+        let unixtime = sample.system.unixtime;
+
+        self.system.refresh_specifics(self.refresh);
+
+        // Network and disk counters are totals since the last refresh, so they are turned into rates
+        let elapsed = self.refreshed_at.elapsed().as_secs_f32();
+        self.refreshed_at = Instant::now();
+
+        let snapshot = &mut sample.system;
+
+        if self.cpu {
+            let load = self.system.load_average();
+
+            snapshot.cpu_pressure = load.one as _;
+            snapshot.cpu_usage = self.system.global_processor_info().cpu_usage() / 100.0;
+            snapshot.core_usage = self
+                .system
+                .processors()
+                .iter()
+                .map(ProcessorExt::cpu_usage)
+                .collect();
+            snapshot.load = Some(LoadAverage {
+                one: load.one as _,
+                five: load.five as _,
+                fifteen: load.fifteen as _,
+            });
+        }
+
+        if self.memory {
+            snapshot.mem_used_bytes = self.system.used_memory() * Self::KB;
+            snapshot.mem_total_bytes = self.system.total_memory() * Self::KB;
+            // Left at zero when the total could not be read, rather than dividing by it
+            if snapshot.mem_total_bytes > 0 {
+                snapshot.mem_pressure =
+                    snapshot.mem_used_bytes as f32 / snapshot.mem_total_bytes as f32;
+            }
+            snapshot.swap_used_bytes = self.system.used_swap() * Self::KB;
+            snapshot.swap_total_bytes = self.system.total_swap() * Self::KB;
+        }
+
+        if self.refresh.disks() {
+            snapshot.disks = self
+                .system
+                .disks()
+                .iter()
+                .map(|disk| DiskUsage {
+                    name: disk.name().to_string_lossy().into_owned(),
+                    mount_point: disk.mount_point().display().to_string(),
+                    file_system: String::from_utf8_lossy(disk.file_system()).into_owned(),
+                    total_bytes: disk.total_space(),
+                    available_bytes: disk.available_space(),
+                })
+                .collect();
+        }
+
+        if self.refresh.networks() && elapsed > 0.0 {
+            snapshot.networks = self
+                .system
+                .networks()
+                .iter()
+                .map(|(interface, data)| NetworkUsage {
+                    interface: interface.clone(),
+                    rx_bytes_per_second: data.received() as f32 / elapsed,
+                    tx_bytes_per_second: data.transmitted() as f32 / elapsed,
+                })
+                .collect();
         }
+
+        if self.pressure {
+            snapshot.pressure = cgroups::system_pressure();
+        }
+
+        let children = processes::children(&self.system);
+        let processes = &mut sample.processes;
+        processes.extend(
+            self.instances
+                .iter()
+                .map(|it| it.snapshot(&self.system, &children, unixtime, elapsed)),
+        );
+
+        if let Some(ref mut cgroups) = self.cgroups {
+            snapshot.cgroup = Cgroups::of("self").and_then(|it| cgroups.usage(&it, elapsed));
+
+            // Host wide memory is misleading when the manager can only use part of it
+            if let Some(ref cgroup) = snapshot.cgroup {
+                if cgroup.memory_max_bytes > 0 && cgroup.memory_max_bytes < snapshot.mem_total_bytes
+                {
+                    snapshot.mem_pressure = cgroup.memory_pressure;
+                }
+            }
+
+            for process in processes.iter_mut() {
+                process.cgroup = process
+                    .pids
+                    .first()
+                    .and_then(Cgroups::of)
+                    .and_then(|it| cgroups.usage(&it, elapsed));
+            }
+
+            cgroups.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{sample, start_sysinfo, Resolution, Sampler, Scripted};
+    use crate::{application::Config, prelude::*};
+
+    #[tokio::test(start_paused = true)]
+    async fn samples_from_sources() {
+        let mut config = Config::default();
+        config.sysinfo.interval = Duration::from_secs(10);
+        config.sysinfo.history.persist = false;
+
+        let script = Scripted::new((0..5).map(|it| sample(600 + it * 10, 0.5)));
+        let info = start_sysinfo(&config, Sampler::new().register(script.clone()));
+
+        // Idle until subscribed, history aside
+        let mut samples = info.clone().collect();
+        let taken = samples
+            .by_ref()
+            .take(5)
+            .map(|it| it.system.unixtime)
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(taken, [600, 610, 620, 630, 640]);
+        assert_eq!(script.remaining(), 0);
+
+        // Let history catch up with the subscription
+        tokio::task::yield_now().await;
+
+        let (resolution, history) = info.history(0, u64::MAX, None, usize::MAX).unwrap();
+        assert_eq!(resolution, Resolution::Raw);
+        assert_eq!(history.len(), 5);
+
+        // Once the script runs out the last sample is repeated
+        script.extend([sample(700, 0.75)]);
+        assert_eq!(samples.next().await.unwrap().system.unixtime, 700);
+        assert_eq!(samples.next().await.unwrap().system.unixtime, 700);
+        tokio::task::yield_now().await;
+        assert_eq!(
+            info.history(0, u64::MAX, None, usize::MAX).unwrap().1.len(),
+            7
+        );
     }
 }
@@ -0,0 +1,511 @@
+//! Alerts raised when snapshot fields cross the thresholds of configured rules
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::bail;
+use data::events::{AlertCleared, AlertRaised, Event};
+use tracing::{info, warn};
+
+use super::{Sample, Subscription, SystemInfo};
+use crate::{
+    application::{AlertMetric, AlertRule, Config, MetricGroup},
+    bus::{EventBus, Publisher},
+    prelude::*,
+};
+
+impl AlertMetric {
+    /// Name of the metric, as written in the config
+    fn name(&self) -> &'static str {
+        match self {
+            AlertMetric::CpuUsage => "cpu_usage",
+            AlertMetric::LoadOne => "load_one",
+            AlertMetric::MemoryUsage => "memory_usage",
+            AlertMetric::MemoryUsedBytes => "memory_used_bytes",
+            AlertMetric::SwapUsedBytes => "swap_used_bytes",
+            AlertMetric::DiskAvailableBytes => "disk_available_bytes",
+            AlertMetric::DiskUsage => "disk_usage",
+            AlertMetric::NetworkRxBytesPerSecond => "network_rx_bytes_per_second",
+            AlertMetric::NetworkTxBytesPerSecond => "network_tx_bytes_per_second",
+            AlertMetric::InstanceCpuUsage => "instance_cpu_usage",
+            AlertMetric::InstanceRssBytes => "instance_rss_bytes",
+        }
+    }
+
+    /// The group which has to be collected for the metric to have a value
+    fn group(&self) -> MetricGroup {
+        match self {
+            AlertMetric::CpuUsage | AlertMetric::LoadOne => MetricGroup::Cpu,
+            AlertMetric::MemoryUsage
+            | AlertMetric::MemoryUsedBytes
+            | AlertMetric::SwapUsedBytes => MetricGroup::Memory,
+            AlertMetric::DiskAvailableBytes | AlertMetric::DiskUsage => MetricGroup::Disks,
+            AlertMetric::NetworkRxBytesPerSecond | AlertMetric::NetworkTxBytesPerSecond => {
+                MetricGroup::Networks
+            }
+            AlertMetric::InstanceCpuUsage | AlertMetric::InstanceRssBytes => MetricGroup::Processes,
+        }
+    }
+
+    /// Instance an alert for `subject` is about, empty when the metric is system wide
+    fn instance(&self, subject: &str) -> String {
+        match self.group() {
+            MetricGroup::Processes => subject.into(),
+            _ => String::new(),
+        }
+    }
+
+    /// Each value of the metric in `sample`, by the subject it is for
+    fn values(&self, sample: &Sample) -> Vec<(String, f64)> {
+        let system = &sample.system;
+        let whole = |value: f64| Vec::from([(String::new(), value)]);
+        let running = || sample.processes.iter().filter(|it| it.running);
+
+        match self {
+            AlertMetric::CpuUsage => whole(system.cpu_usage.into()),
+            AlertMetric::LoadOne => whole(system.load.as_ref().map_or(0.0, |it| it.one.into())),
+            AlertMetric::MemoryUsage => whole(system.mem_pressure.into()),
+            AlertMetric::MemoryUsedBytes => whole(system.mem_used_bytes as f64),
+            AlertMetric::SwapUsedBytes => whole(system.swap_used_bytes as f64),
+            AlertMetric::DiskAvailableBytes => system
+                .disks
+                .iter()
+                .map(|it| (it.mount_point.clone(), it.available_bytes as f64))
+                .collect(),
+            AlertMetric::DiskUsage => system
+                .disks
+                .iter()
+                .filter(|it| it.total_bytes > 0)
+                .map(|it| {
+                    let usage = 1.0 - it.available_bytes as f64 / it.total_bytes as f64;
+                    (it.mount_point.clone(), usage)
+                })
+                .collect(),
+            AlertMetric::NetworkRxBytesPerSecond => system
+                .networks
+                .iter()
+                .map(|it| (it.interface.clone(), it.rx_bytes_per_second.into()))
+                .collect(),
+            AlertMetric::NetworkTxBytesPerSecond => system
+                .networks
+                .iter()
+                .map(|it| (it.interface.clone(), it.tx_bytes_per_second.into()))
+                .collect(),
+            AlertMetric::InstanceCpuUsage => running()
+                .map(|it| (it.instance.clone(), it.cpu_usage.into()))
+                .collect(),
+            AlertMetric::InstanceRssBytes => running()
+                .map(|it| (it.instance.clone(), it.rss_bytes as f64))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Above,
+    Below,
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    metric: AlertMetric,
+    direction: Direction,
+    threshold: f64,
+    clear: f64,
+    /// Seconds the threshold must stay crossed for
+    duration: u64,
+    /// Seconds after clearing before raising again
+    cooldown: u64,
+    subjects: HashMap<String, State>,
+}
+
+/// Where a rule is at for one subject
+#[derive(Debug, Default)]
+struct State {
+    /// When the threshold was crossed, while it still is
+    crossed_at: Option<u64>,
+    raised: Option<AlertRaised>,
+    cleared_at: Option<u64>,
+}
+
+impl Rule {
+    fn new(config: &AlertRule, metrics: &[MetricGroup]) -> Result<Self> {
+        let name = &config.name;
+
+        let (direction, threshold) = match (config.above, config.below) {
+            (Some(above), None) => (Direction::Above, above),
+            (None, Some(below)) => (Direction::Below, below),
+            _ => bail!("Alert rule {name} must set exactly one of above and below"),
+        };
+
+        let clear = config.clear.unwrap_or(threshold);
+        let inside = match direction {
+            Direction::Above => clear <= threshold,
+            Direction::Below => clear >= threshold,
+        };
+
+        if !inside {
+            bail!("Alert rule {name} would clear at {clear}, which is past its threshold");
+        }
+
+        if !metrics.contains(&config.metric.group()) {
+            bail!(
+                "Alert rule {name} checks {}, but the {:?} metric group is not collected",
+                config.metric.name(),
+                config.metric.group(),
+            );
+        }
+
+        Ok(Self {
+            name: name.clone(),
+            metric: config.metric,
+            direction,
+            threshold,
+            clear,
+            duration: config.duration.as_secs(),
+            cooldown: config.cooldown.as_secs(),
+            subjects: HashMap::new(),
+        })
+    }
+
+    /// Check the value of one subject at `now`, returning the event if the alert was raised or
+    /// cleared by it
+    fn check(&mut self, subject: String, value: f64, now: u64) -> Option<Event> {
+        let (crossed, cleared) = match self.direction {
+            Direction::Above => (value > self.threshold, value <= self.clear),
+            Direction::Below => (value < self.threshold, value >= self.clear),
+        };
+
+        let state = self.subjects.entry(subject.clone()).or_default();
+
+        if let Some(raised) = &state.raised {
+            if !cleared {
+                return None;
+            }
+
+            let event = AlertCleared {
+                unixtime: now,
+                rule: self.name.clone(),
+                metric: self.metric.name().into(),
+                instance: raised.instance.clone(),
+                subject,
+                value,
+                threshold: self.clear,
+                raised_unixtime: raised.unixtime,
+                gone: false,
+            };
+
+            state.raised = None;
+            state.crossed_at = None;
+            state.cleared_at = Some(now);
+
+            return Some(Event::AlertCleared(event));
+        }
+
+        if !crossed {
+            state.crossed_at = None;
+            return None;
+        }
+
+        let crossed_at = *state.crossed_at.get_or_insert(now);
+        let cooling_down = state
+            .cleared_at
+            .map(|it| now < it + self.cooldown)
+            .unwrap_or(false);
+
+        if now < crossed_at + self.duration || cooling_down {
+            return None;
+        }
+
+        let event = AlertRaised {
+            unixtime: now,
+            rule: self.name.clone(),
+            metric: self.metric.name().into(),
+            instance: self.metric.instance(&subject),
+            subject,
+            value,
+            threshold: self.threshold,
+        };
+        state.raised = Some(event.clone());
+
+        Some(Event::AlertRaised(event))
+    }
+
+    /// Forget every subject which is not in `present`, clearing its alert if it is raised
+    ///
+    /// Subjects go missing when an instance stops or a disk is unmounted, and would otherwise
+    /// never be checked again.
+    fn forget_missing(&mut self, present: &HashSet<String>, now: u64) -> Vec<Event> {
+        let missing = self
+            .subjects
+            .keys()
+            .filter(|it| !present.contains(*it))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        missing
+            .into_iter()
+            .filter_map(|subject| {
+                let raised = self.subjects.remove(&subject)?.raised?;
+
+                Some(Event::AlertCleared(AlertCleared {
+                    unixtime: now,
+                    rule: self.name.clone(),
+                    metric: self.metric.name().into(),
+                    instance: raised.instance,
+                    subject,
+                    value: raised.value,
+                    threshold: self.clear,
+                    raised_unixtime: raised.unixtime,
+                    gone: true,
+                }))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct Evaluator {
+    rules: Vec<Rule>,
+}
+
+impl Evaluator {
+    fn evaluate(&mut self, sample: &Sample) -> Vec<Event> {
+        let now = sample.system.unixtime;
+        let mut events = Vec::new();
+
+        for rule in &mut self.rules {
+            let values = rule.metric.values(sample);
+            let present = values.iter().map(|(it, _)| it.clone()).collect();
+
+            for (subject, value) in values {
+                events.extend(rule.check(subject, value, now));
+            }
+
+            events.extend(rule.forget_missing(&present, now));
+        }
+
+        events
+    }
+
+    fn active(&self) -> Vec<AlertRaised> {
+        self.rules
+            .iter()
+            .flat_map(|rule| rule.subjects.values())
+            .filter_map(|state| state.raised.clone())
+            .collect()
+    }
+}
+
+/// Handle to the alert rules, which publishes alerts on the event bus as they are raised and
+/// cleared
+#[derive(Debug, Clone)]
+pub struct Alerts {
+    raised: Publisher<AlertRaised>,
+    cleared: Publisher<AlertCleared>,
+    evaluator: Arc<Mutex<Evaluator>>,
+}
+
+impl Alerts {
+    /// Alerts which are currently raised
+    pub fn active(&self) -> Vec<AlertRaised> {
+        self.evaluator.lock().unwrap().active()
+    }
+}
+
+/// Start a task which checks the configured alert rules against every sample
+///
+/// Fails if any of the rules are invalid.
+pub fn start_alerts(config: &Config, info: &SystemInfo, bus: &EventBus) -> Result<Alerts> {
+    let rules = config
+        .alerts
+        .iter()
+        .map(|it| Rule::new(it, &config.sysinfo.metrics))
+        .collect::<Result<Vec<_>>>()?;
+    let checking = !rules.is_empty();
+
+    let alerts = Alerts {
+        raised: bus.publisher(),
+        cleared: bus.publisher(),
+        evaluator: Arc::new(Mutex::new(Evaluator { rules })),
+    };
+
+    if checking {
+        tokio::task::Builder::new()
+            .name("Sysinfo alerts")
+            .spawn(check_alerts(info.clone().collect(), alerts.clone()));
+    }
+
+    Ok(alerts)
+}
+
+async fn check_alerts(mut samples: Subscription, alerts: Alerts) {
+    while let Some(sample) = samples.next().await {
+        let events = alerts.evaluator.lock().unwrap().evaluate(&sample);
+
+        for event in events {
+            match event {
+                Event::AlertRaised(it) => {
+                    warn!(
+                        "Alert {} raised for {:?}, {} is {}",
+                        it.rule, it.subject, it.metric, it.value
+                    );
+                    alerts.raised.publish(it);
+                }
+                Event::AlertCleared(it) => {
+                    info!(
+                        "Alert {} cleared for {:?}, {} is {}",
+                        it.rule, it.subject, it.metric, it.value
+                    );
+                    alerts.cleared.publish(it);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use data::events::{Event, ProcessSnapshot, SystemSnapshot};
+
+    use super::{Evaluator, Rule};
+    use crate::{
+        application::{AlertMetric, AlertRule, MetricGroup},
+        information::Sample,
+    };
+
+    fn rule() -> AlertRule {
+        AlertRule {
+            name: "memory".into(),
+            metric: AlertMetric::MemoryUsage,
+            above: Some(0.9),
+            below: None,
+            clear: Some(0.75),
+            duration: Duration::from_secs(120),
+            cooldown: Duration::from_secs(600),
+        }
+    }
+
+    fn memory(unixtime: u64, usage: f32) -> Sample {
+        Sample {
+            system: SystemSnapshot {
+                unixtime,
+                mem_pressure: usage,
+                ..Default::default()
+            },
+            processes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn raise_and_clear() {
+        let mut rule = Rule::new(&rule(), &[MetricGroup::Memory]).unwrap();
+        let mut check = |unixtime, usage| {
+            let sample = memory(unixtime, usage);
+            let (subject, value) = rule.metric.values(&sample).remove(0);
+
+            match rule.check(subject, value, unixtime) {
+                Some(Event::AlertRaised(_)) => "raised",
+                Some(Event::AlertCleared(_)) => "cleared",
+                Some(_) => unreachable!(),
+                None => "",
+            }
+        };
+
+        // Has to stay above the threshold for long enough
+        assert_eq!(check(0, 0.95), "");
+        assert_eq!(check(60, 0.5), "");
+        assert_eq!(check(100, 0.95), "");
+        assert_eq!(check(200, 0.95), "");
+        assert_eq!(check(220, 0.95), "raised");
+        assert_eq!(check(230, 0.95), "");
+
+        // Does not clear until back past the clear threshold
+        assert_eq!(check(240, 0.8), "");
+        assert_eq!(check(250, 0.75), "cleared");
+
+        // Can not be raised again until after the cooldown
+        assert_eq!(check(300, 0.95), "");
+        assert_eq!(check(500, 0.95), "");
+        assert_eq!(check(850, 0.95), "raised");
+    }
+
+    #[test]
+    fn clears_subjects_which_are_gone() {
+        let config = AlertRule {
+            metric: AlertMetric::InstanceRssBytes,
+            above: Some(100.0),
+            clear: None,
+            duration: Duration::ZERO,
+            ..rule()
+        };
+        let mut evaluator = Evaluator {
+            rules: Vec::from([Rule::new(&config, &[MetricGroup::Processes]).unwrap()]),
+        };
+
+        let process = |instance: &str| ProcessSnapshot {
+            instance: instance.into(),
+            running: true,
+            rss_bytes: 200,
+            ..Default::default()
+        };
+        let sample = |unixtime, processes| Sample {
+            system: SystemSnapshot {
+                unixtime,
+                ..Default::default()
+            },
+            processes,
+        };
+
+        let raised = evaluator.evaluate(&sample(10, Vec::from([process("a"), process("b")])));
+        assert_eq!(raised.len(), 2);
+        assert_eq!(evaluator.active().len(), 2);
+
+        // Instance a stopped
+        let events = evaluator.evaluate(&sample(20, Vec::from([process("b")])));
+        match events.as_slice() {
+            [Event::AlertCleared(cleared)] => {
+                assert_eq!(cleared.subject, "a");
+                assert_eq!(cleared.instance, "a");
+                assert_eq!(cleared.raised_unixtime, 10);
+                assert!(cleared.gone);
+            }
+            other => panic!("Expected a to clear, got {other:?}"),
+        }
+
+        let active = evaluator.active();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].subject, "b");
+        assert_eq!(active[0].instance, "b");
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let groups = [MetricGroup::Memory];
+
+        let both = AlertRule {
+            below: Some(0.1),
+            ..rule()
+        };
+        assert!(Rule::new(&both, &groups).is_err());
+
+        let clear_past_threshold = AlertRule {
+            clear: Some(0.95),
+            ..rule()
+        };
+        assert!(Rule::new(&clear_past_threshold, &groups).is_err());
+
+        let not_collected = AlertRule {
+            metric: AlertMetric::DiskAvailableBytes,
+            ..rule()
+        };
+        assert!(Rule::new(&not_collected, &groups).is_err());
+    }
+}
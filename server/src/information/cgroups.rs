@@ -0,0 +1,269 @@
+//! Limits and usage of cgroup v2 control groups, and pressure stall information
+//!
+//! In a container or a systemd slice, the host wide numbers from sysinfo do not reflect what the
+//! manager and its servers may actually use, so these are read straight from the kernel.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use data::events::{CgroupUsage, Pressure, PressureStall};
+
+/// Reads cgroups out of the cgroup v2 hierarchy
+pub(super) struct Cgroups {
+    /// Where the hierarchy is mounted
+    root: PathBuf,
+    /// `usage_usec` from `cpu.stat` in the previous sample, by cgroup
+    previous: HashMap<String, u64>,
+    /// `usage_usec` from `cpu.stat` in this sample, by cgroup
+    current: HashMap<String, u64>,
+}
+
+impl Cgroups {
+    /// Find where the cgroup v2 hierarchy is mounted, if it is and has any controllers
+    ///
+    /// Hosts in hybrid mode mount a cgroup v2 hierarchy alongside v1, but without any controllers
+    /// there is nothing to read from it.
+    pub(super) fn detect() -> Option<Self> {
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+
+        let root = mountinfo.lines().find_map(|line| {
+            // The filesystem type follows the separator, and the mount point is the fifth field
+            let (fields, filesystem) = line.split_once(" - ")?;
+
+            if filesystem.split(' ').next()? != "cgroup2" {
+                return None;
+            }
+
+            fields.split(' ').nth(4)
+        })?;
+
+        let controllers = read(&Path::new(root).join("cgroup.controllers"))?;
+        if controllers.trim().is_empty() {
+            return None;
+        }
+
+        Some(Self::new(root.into()))
+    }
+
+    fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            previous: HashMap::new(),
+            current: HashMap::new(),
+        }
+    }
+
+    /// The cgroup v2 path of a process, or of the manager for `"self"`
+    pub(super) fn of(pid: impl Display) -> Option<String> {
+        std::fs::read_to_string(format!("/proc/{pid}/cgroup"))
+            .ok()?
+            .lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .map(str::to_owned)
+    }
+
+    /// Limits and usage of the cgroup at `path`
+    ///
+    /// `elapsed` is the time in seconds since the previous sample, which CPU usage is averaged over
+    pub(super) fn usage(&mut self, path: &str, elapsed: f32) -> Option<CgroupUsage> {
+        let dir = self.root.join(path.trim_start_matches('/'));
+
+        if !dir.is_dir() {
+            return None;
+        }
+
+        let mut usage = CgroupUsage {
+            path: path.to_owned(),
+            memory_current_bytes: read(&dir.join("memory.current"))
+                .and_then(|it| it.trim().parse().ok())
+                .unwrap_or(0),
+            pressure: read_pressure(|resource| dir.join(format!("{resource}.pressure"))),
+            ..Default::default()
+        };
+
+        // Limits on parents apply as well, so whichever is lowest is the one in effect. Unlimited
+        // cgroups have `max` in place of a number, and the root has no limit files at all.
+        let mut memory_max: Option<u64> = None;
+        let mut cpu_limit: Option<f32> = None;
+
+        for cgroup in dir.ancestors().take_while(|it| it.starts_with(&self.root)) {
+            if let Some(max) =
+                read(&cgroup.join("memory.max")).and_then(|it| it.trim().parse().ok())
+            {
+                memory_max = Some(memory_max.map_or(max, |it| it.min(max)));
+            }
+
+            if let Some(limit) = read(&cgroup.join("cpu.max")).and_then(|it| parse_cpu_max(&it)) {
+                cpu_limit = Some(cpu_limit.map_or(limit, |it| it.min(limit)));
+            }
+        }
+
+        if let Some(max) = memory_max.filter(|it| *it > 0) {
+            usage.memory_max_bytes = max;
+            usage.memory_pressure = usage.memory_current_bytes as f32 / max as f32;
+        }
+
+        usage.cpu_limit = cpu_limit.unwrap_or(0.0);
+
+        if let Some(stat) = read(&dir.join("cpu.stat")) {
+            let field = |name: &str| {
+                stat.lines().find_map(|line| {
+                    let (key, value) = line.split_once(' ')?;
+
+                    if key != name {
+                        return None;
+                    }
+
+                    value.trim().parse::<u64>().ok()
+                })
+            };
+
+            usage.cpu_throttled_us = field("throttled_usec").unwrap_or(0);
+
+            if let Some(used) = field("usage_usec") {
+                self.current.insert(path.to_owned(), used);
+
+                match self.previous.get(path) {
+                    Some(previous) if elapsed > 0.0 => {
+                        usage.cpu_usage = used.saturating_sub(*previous) as f32 / 1e6 / elapsed;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Some(usage)
+    }
+
+    /// Finish a sample, forgetting cgroups which were not read during it
+    pub(super) fn finish(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
+}
+
+/// System wide pressure stall information, if the kernel reports it
+pub(super) fn system_pressure() -> Option<PressureStall> {
+    read_pressure(|resource| Path::new("/proc/pressure").join(resource))
+}
+
+fn read(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path).ok()
+}
+
+/// Cores allowed by the `$QUOTA $PERIOD` in `cpu.max`, or `None` for `max`
+fn parse_cpu_max(contents: &str) -> Option<f32> {
+    let mut fields = contents.split_whitespace();
+    let quota: f32 = fields.next()?.parse().ok()?;
+    let period: f32 = fields.next()?.parse().ok()?;
+
+    (period > 0.0).then(|| quota / period)
+}
+
+/// Read the pressure of each resource from the file `path` gives for it
+fn read_pressure(path: impl Fn(&str) -> PathBuf) -> Option<PressureStall> {
+    let cpu = read(&path("cpu")).map(|it| parse_pressure(&it));
+    let memory = read(&path("memory")).map(|it| parse_pressure(&it));
+    let io = read(&path("io")).map(|it| parse_pressure(&it));
+
+    if cpu.is_none() && memory.is_none() && io.is_none() {
+        return None;
+    }
+
+    Some(PressureStall { cpu, memory, io })
+}
+
+/// Parse lines such as `some avg10=0.00 avg60=0.00 avg300=0.00 total=0`
+fn parse_pressure(contents: &str) -> Pressure {
+    let mut pressure = Pressure::default();
+
+    for line in contents.lines() {
+        let mut fields = line.split_whitespace();
+        let kind = fields.next();
+
+        for (key, value) in fields.filter_map(|it| it.split_once('=')) {
+            let avg = || value.parse().unwrap_or(0.0);
+            let total = || value.parse().unwrap_or(0);
+
+            match (kind, key) {
+                (Some("some"), "avg10") => pressure.some_avg10 = avg(),
+                (Some("some"), "avg60") => pressure.some_avg60 = avg(),
+                (Some("some"), "avg300") => pressure.some_avg300 = avg(),
+                (Some("some"), "total") => pressure.some_total_us = total(),
+                (Some("full"), "avg10") => pressure.full_avg10 = avg(),
+                (Some("full"), "avg60") => pressure.full_avg60 = avg(),
+                (Some("full"), "avg300") => pressure.full_avg300 = avg(),
+                (Some("full"), "total") => pressure.full_total_us = total(),
+                _ => {}
+            }
+        }
+    }
+
+    pressure
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use tempdir::TempDir;
+
+    use super::Cgroups;
+
+    fn write(path: &Path, contents: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn limits_from_parents() {
+        let root = TempDir::new("cgroup").unwrap();
+        let slice = root.path().join("minecraft.slice");
+        let service = slice.join("survival.service");
+
+        write(&slice.join("memory.max"), "4294967296\n");
+        write(&slice.join("cpu.max"), "max 100000\n");
+        write(&service.join("memory.max"), "max\n");
+        write(&service.join("memory.current"), "1073741824\n");
+        write(&service.join("cpu.max"), "200000 100000\n");
+        write(
+            &service.join("cpu.stat"),
+            "usage_usec 1000000\nuser_usec 800000\nthrottled_usec 5000\n",
+        );
+        write(
+            &service.join("memory.pressure"),
+            "some avg10=1.50 avg60=0.75 avg300=0.25 total=12345\n\
+             full avg10=0.50 avg60=0.00 avg300=0.00 total=678\n",
+        );
+
+        let mut cgroups = Cgroups::new(root.path().into());
+        let path = "/minecraft.slice/survival.service";
+
+        let usage = cgroups.usage(path, 1.0).unwrap();
+        assert_eq!(usage.path, path);
+        assert_eq!(usage.memory_current_bytes, 1 << 30);
+        assert_eq!(usage.memory_max_bytes, 4 << 30);
+        assert_eq!(usage.memory_pressure, 0.25);
+        assert_eq!(usage.cpu_limit, 2.0);
+        assert_eq!(usage.cpu_throttled_us, 5000);
+        assert_eq!(usage.cpu_usage, 0.0, "No previous sample to compare with");
+
+        let pressure = usage.pressure.unwrap();
+        assert!(pressure.cpu.is_none());
+        let memory = pressure.memory.unwrap();
+        assert_eq!(memory.some_avg10, 1.5);
+        assert_eq!(memory.some_total_us, 12345);
+        assert_eq!(memory.full_avg10, 0.5);
+        assert_eq!(memory.full_total_us, 678);
+
+        cgroups.finish();
+        write(&service.join("cpu.stat"), "usage_usec 3000000\n");
+
+        let usage = cgroups.usage(path, 2.0).unwrap();
+        assert_eq!(usage.cpu_usage, 1.0);
+
+        assert!(cgroups.usage("/missing.slice", 1.0).is_none());
+    }
+}
@@ -0,0 +1,548 @@
+//! Garbage collection pauses of managed instances, tailed from their unified JVM GC logs
+//!
+//! Lag on a Minecraft server is most often a long collection, so every pause is published as a
+//! [`GcEvent`] as soon as it is written, and the pauses of the last few minutes are summed up in
+//! each instance's process snapshot.
+//!
+//! Only what is written after the manager starts watching is published. Anything already in the
+//! log is read for which collector is in use, but its pauses are skipped.
+//!
+//! Instances the manager launches are given [`gc_log_flag`] with their other JVM arguments. Any
+//! other instance has to be started with it by whatever launches it, and the flag is logged for any
+//! instance whose log does not exist.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_inotify::handle::Handle;
+use data::events::{GcEvent, GcStats};
+use tracing::{debug, info, warn};
+
+use super::{MetricSource, Sample};
+use crate::{
+    application::{Config, MetricGroup},
+    bus::{EventBus, Publisher},
+    prelude::*,
+};
+
+/// How far back pauses are summed up in [`GcStats`], in seconds
+const WINDOW: u64 = 5 * 60;
+
+/// Time between checks for a log directory which does not exist yet
+const RETRY: Duration = Duration::from_secs(30);
+
+/// JVM flag which writes the GC log read here to `path`, rotating between five 10MB files
+pub fn gc_log_flag(path: &Path) -> String {
+    format!(
+        "-Xlog:gc*:file={}:time,uptime,level,tags:filecount=5,filesize=10m",
+        path.display()
+    )
+}
+
+/// Handle to the GC log tailers, which publish pauses on the event bus as they happen
+#[derive(Debug, Clone)]
+pub struct GcLogs {
+    publisher: Publisher<GcEvent>,
+    windows: Arc<Mutex<HashMap<String, Window>>>,
+}
+
+impl GcLogs {
+    fn new(bus: &EventBus) -> Self {
+        Self {
+            publisher: bus.publisher(),
+            windows: Default::default(),
+        }
+    }
+
+    /// Source which adds the rolling [`GcStats`] of each instance to its process snapshot
+    pub fn source(&self) -> GcSource {
+        GcSource {
+            windows: self.windows.clone(),
+        }
+    }
+
+    /// Publish a pause of `instance`, and add it to the instance's window
+    fn record(&self, event: GcEvent) {
+        self.windows
+            .lock()
+            .unwrap()
+            .entry(event.instance.clone())
+            .or_default()
+            .record(&event);
+
+        debug!(
+            "{} paused {}ms for GC({}) {}",
+            event.instance, event.pause_ms, event.gc_id, event.pause
+        );
+
+        self.publisher.publish(event);
+    }
+
+    fn set_collector(&self, instance: &str, collector: &str) {
+        self.windows
+            .lock()
+            .unwrap()
+            .entry(instance.to_owned())
+            .or_default()
+            .collector = collector.to_owned();
+    }
+}
+
+/// Start a task for each managed instance which tails its GC log, when the gc metric group is
+/// collected
+pub fn start_gc(config: &Config, inotify: &Handle, bus: &EventBus) -> GcLogs {
+    let logs = GcLogs::new(bus);
+
+    if !config.sysinfo.metrics.contains(&MetricGroup::Gc) {
+        return logs;
+    }
+
+    for (id, instance) in config.instances.iter() {
+        tokio::task::Builder::new().name("GC log").spawn(tail(
+            id.clone(),
+            instance.root.join(&instance.gc_log),
+            gc_log_flag(&instance.gc_log),
+            inotify.clone(),
+            logs.clone(),
+        ));
+    }
+
+    logs
+}
+
+/// Adds [`GcStats`] to the process snapshot of each instance which has written to its GC log
+#[derive(Debug, Clone)]
+pub struct GcSource {
+    windows: Arc<Mutex<HashMap<String, Window>>>,
+}
+
+impl MetricSource for GcSource {
+    fn sample(&mut self, sample: &mut Sample) {
+        let now = sample.system.unixtime;
+        let mut windows = self.windows.lock().unwrap();
+
+        for process in sample.processes.iter_mut() {
+            if let Some(window) = windows.get_mut(&process.instance) {
+                process.gc = Some(window.stats(now));
+            }
+        }
+    }
+}
+
+/// Pauses of one instance within the last [`WINDOW`]
+#[derive(Debug, Default)]
+struct Window {
+    collector: String,
+    /// When each pause was read, and how long it was in milliseconds
+    pauses: VecDeque<(u64, f32)>,
+    heap_after_bytes: u64,
+    heap_total_bytes: u64,
+}
+
+impl Window {
+    fn record(&mut self, event: &GcEvent) {
+        self.pauses.push_back((event.unixtime, event.pause_ms));
+
+        if event.heap_total_bytes > 0 {
+            self.heap_after_bytes = event.heap_after_bytes;
+            self.heap_total_bytes = event.heap_total_bytes;
+        }
+    }
+
+    fn stats(&mut self, now: u64) -> GcStats {
+        while matches!(self.pauses.front(), Some((at, _)) if at + WINDOW < now) {
+            self.pauses.pop_front();
+        }
+
+        let pauses = self.pauses.iter().map(|(_, ms)| *ms);
+        let total: f32 = pauses.clone().sum();
+
+        GcStats {
+            collector: self.collector.clone(),
+            window_seconds: WINDOW as u32,
+            pauses: self.pauses.len() as u32,
+            pause_total_ms: total,
+            pause_max_ms: pauses.fold(0.0, f32::max),
+            pause_mean_ms: if self.pauses.is_empty() {
+                0.0
+            } else {
+                total / self.pauses.len() as f32
+            },
+            paused: total / (WINDOW * 1000) as f32,
+            heap_after_bytes: self.heap_after_bytes,
+            heap_total_bytes: self.heap_total_bytes,
+        }
+    }
+}
+
+/// Follow the GC log of `instance` at `path` until the inotify watcher shuts down
+///
+/// The log's directory is watched rather than the log itself, since the JVM replaces the log when
+/// it starts and each time it rotates.
+async fn tail(instance: String, path: PathBuf, flag: String, mut inotify: Handle, logs: GcLogs) {
+    let dir = path.parent().unwrap_or_else(|| Path::new("/")).to_owned();
+    let name = path.file_name().map(|it| it.to_string_lossy().into_owned());
+    let mut told = false;
+
+    let mut events = loop {
+        let watch = match inotify.dir(dir.clone()) {
+            Ok(it) => it.modify(true).watch().await.map_err(anyhow::Error::from),
+            Err(e) => Err(e.into()),
+        };
+
+        match watch {
+            Ok(watch) => break watch,
+            Err(e) if !told => {
+                info!(
+                    "Not watching GC log of {instance} yet ({e}), start it with {flag} to report garbage collection"
+                );
+                told = true;
+            }
+            Err(_) => {}
+        }
+
+        tokio::time::sleep(RETRY).await;
+    };
+
+    if !told && !path.exists() {
+        info!("No GC log from {instance} yet, start it with {flag} to report garbage collection");
+    }
+
+    let mut log = Log::new(path);
+
+    // What was logged before the manager started is only read for the collector
+    if let Err(e) = tokio::task::block_in_place(|| log.read(&instance, &logs, false)) {
+        warn!("Could not read GC log of {instance}: {e:#}");
+    }
+
+    while let Some(event) = events.next().await {
+        if event.inner_path != name {
+            continue;
+        }
+
+        if let Err(e) = tokio::task::block_in_place(|| log.read(&instance, &logs, true)) {
+            warn!("Could not read GC log of {instance}: {e:#}");
+        }
+    }
+
+    debug!("Stopped watching GC log of {instance}");
+}
+
+/// Position in a log which may be replaced or truncated while it is followed
+struct Log {
+    path: PathBuf,
+    file: Option<File>,
+    inode: u64,
+    offset: u64,
+    /// The start of a line which has not been completely written yet
+    partial: String,
+    collector: String,
+}
+
+impl Log {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            file: None,
+            inode: 0,
+            offset: 0,
+            partial: String::new(),
+            collector: String::new(),
+        }
+    }
+
+    /// Read everything written since the last read, publishing pauses if `publish` is set
+    fn read(&mut self, instance: &str, logs: &GcLogs, publish: bool) -> Result<()> {
+        let metadata = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("Reading {}", self.path.display())),
+        };
+
+        // Replaced by a restart or a rotation, or truncated, so start over from the beginning
+        if self.file.is_none() || metadata.ino() != self.inode || metadata.len() < self.offset {
+            self.file = Some(
+                File::open(&self.path)
+                    .with_context(|| format!("Opening {}", self.path.display()))?,
+            );
+            self.inode = metadata.ino();
+            self.offset = 0;
+            self.partial.clear();
+        }
+
+        let file = self.file.as_mut().expect("Opened above");
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(self.offset))?;
+        file.read_to_end(&mut contents)?;
+        self.offset += contents.len() as u64;
+
+        self.partial.push_str(&String::from_utf8_lossy(&contents));
+
+        let complete = match self.partial.rfind('\n') {
+            Some(end) => self.partial.drain(..=end).collect::<String>(),
+            None => return Ok(()),
+        };
+
+        for line in complete.lines() {
+            match parse_line(line) {
+                Some(Line::Collector(collector)) => {
+                    logs.set_collector(instance, &collector);
+                    self.collector = collector;
+                }
+                Some(Line::Pause(pause)) if publish => logs.record(GcEvent {
+                    unixtime: super::unixtime(),
+                    instance: instance.to_owned(),
+                    collector: self.collector.clone(),
+                    ..pause
+                }),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A line of the log which is of interest
+#[derive(Debug, PartialEq)]
+enum Line {
+    /// Which collector the JVM is using, from `Using G1`
+    Collector(String),
+    /// A pause, with only the fields which come from the line itself set
+    Pause(GcEvent),
+}
+
+/// Parse a line such as
+/// `[2022-01-01T00:00:00.000+0000][12.345s][info][gc] GC(3) Pause Young (Normal) (G1 Evacuation Pause) 512M->128M(2048M) 12.345ms`
+///
+/// Only lines tagged with just `gc` are summaries, the rest are details of the phases.
+fn parse_line(line: &str) -> Option<Line> {
+    let mut message = line;
+    let mut tags = None;
+
+    // Whatever decorations were chosen, the tags come last
+    while let Some(rest) = message.strip_prefix('[') {
+        let (decoration, rest) = rest.split_once(']')?;
+        tags = Some(decoration.trim());
+        message = rest;
+    }
+
+    if tags? != "gc" {
+        return None;
+    }
+
+    let message = message.trim();
+
+    if let Some(collector) = message.strip_prefix("Using ") {
+        return Some(Line::Collector(collector.to_owned()));
+    }
+
+    let (gc_id, rest) = message.strip_prefix("GC(")?.split_once(')')?;
+    let mut words = rest
+        .trim()
+        .strip_prefix("Pause ")?
+        .split(' ')
+        .collect::<Vec<_>>();
+
+    let pause_ms = words.pop()?.strip_suffix("ms")?.parse().ok()?;
+    let mut event = GcEvent {
+        gc_id: gc_id.parse().ok()?,
+        pause_ms,
+        ..Default::default()
+    };
+
+    if let Some((before, after, total)) = words.last().and_then(|it| parse_heap(it)) {
+        event.heap_before_bytes = before;
+        event.heap_after_bytes = after;
+        event.heap_total_bytes = total;
+        words.pop();
+    }
+
+    event.pause = words.join(" ");
+
+    Some(Line::Pause(event))
+}
+
+/// Parse a heap change such as `512M->128M(2048M)` into bytes before, after, and in total
+fn parse_heap(heap: &str) -> Option<(u64, u64, u64)> {
+    let (before, rest) = heap.split_once("->")?;
+    let (after, total) = rest.strip_suffix(')')?.split_once('(')?;
+
+    Some((parse_size(before)?, parse_size(after)?, parse_size(total)?))
+}
+
+fn parse_size(size: &str) -> Option<u64> {
+    let unit = match size.chars().last()? {
+        'B' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => return None,
+    };
+
+    Some(size[..size.len() - 1].parse::<u64>().ok()? * unit)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Write, time::Duration};
+
+    use data::events::{Event, GcEvent};
+    use tempdir::TempDir;
+
+    use super::{parse_line, tail, GcLogs, Line, Window};
+    use crate::{
+        bus::{BusSubscription, EventBus, Sequenced},
+        prelude::*,
+    };
+
+    #[test]
+    fn parses_pauses() {
+        assert_eq!(
+            parse_line("[0.011s][info][gc] Using G1"),
+            Some(Line::Collector("G1".into()))
+        );
+
+        assert_eq!(
+            parse_line(
+                "[2022-01-01T00:00:00.000+0000][12.345s][info ][gc] GC(3) Pause Young (Normal) (G1 Evacuation Pause) 512M->128M(2048M) 12.345ms"
+            ),
+            Some(Line::Pause(GcEvent {
+                gc_id: 3,
+                pause: "Young (Normal) (G1 Evacuation Pause)".into(),
+                pause_ms: 12.345,
+                heap_before_bytes: 512 << 20,
+                heap_after_bytes: 128 << 20,
+                heap_total_bytes: 2048 << 20,
+                ..Default::default()
+            }))
+        );
+
+        // Shenandoah reports some pauses without the heap
+        assert_eq!(
+            parse_line("[5.000s][info][gc] GC(7) Pause Init Mark 0.250ms"),
+            Some(Line::Pause(GcEvent {
+                gc_id: 7,
+                pause: "Init Mark".into(),
+                pause_ms: 0.25,
+                ..Default::default()
+            }))
+        );
+
+        assert_eq!(
+            parse_line("[12.345s][info][gc,phases] GC(3)   Evacuate Collection Set: 10.1ms"),
+            None
+        );
+        assert_eq!(
+            parse_line("[1.000s][info][gc] GC(0) Concurrent Mark Cycle"),
+            None
+        );
+    }
+
+    #[test]
+    fn rolling_stats() {
+        let mut window = Window::default();
+        let pause = |unixtime, pause_ms| GcEvent {
+            unixtime,
+            pause_ms,
+            heap_after_bytes: 1,
+            heap_total_bytes: 4,
+            ..Default::default()
+        };
+
+        window.record(&pause(0, 100.0));
+        window.record(&pause(200, 20.0));
+        window.record(&pause(250, 30.0));
+
+        let stats = window.stats(250);
+        assert_eq!(stats.pauses, 3);
+        assert_eq!(stats.pause_max_ms, 100.0);
+        assert_eq!(stats.pause_total_ms, 150.0);
+        assert_eq!(stats.pause_mean_ms, 50.0);
+        assert_eq!(stats.paused, 150.0 / 300_000.0);
+        assert_eq!(stats.heap_total_bytes, 4);
+
+        let stats = window.stats(400);
+        assert_eq!(stats.pauses, 2);
+        assert_eq!(stats.pause_max_ms, 30.0);
+    }
+
+    async fn next(events: &mut BusSubscription) -> GcEvent {
+        match tokio::time::timeout(Duration::from_secs(2), events.next()).await {
+            Ok(Some(Ok(Sequenced {
+                event: Event::GcEvent(event),
+                ..
+            }))) => event,
+            other => panic!("Expected a GC event, got {other:?}"),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tails_log() {
+        let dir = TempDir::new("gc").unwrap();
+        let path = dir.path().join("gc.log");
+        let write = |line: &str, append: bool| {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(append)
+                .truncate(!append)
+                .open(&path)
+                .unwrap();
+
+            writeln!(file, "{line}").unwrap();
+        };
+
+        write("[0.011s][info][gc] Using G1", false);
+        write(
+            "[1.000s][info][gc] GC(0) Pause Young 8M->4M(64M) 1.000ms",
+            true,
+        );
+
+        let inotify = async_inotify::new().unwrap();
+        let bus = EventBus::default();
+        let logs = GcLogs::new(&bus);
+        let mut events = bus.subscribe();
+
+        tokio::spawn(tail(
+            "survival".into(),
+            path.clone(),
+            String::new(),
+            inotify.clone(),
+            logs.clone(),
+        ));
+        tokio::time::sleep(Duration::from_millis(250)).await;
+
+        // Only pauses from after watching started are published
+        write(
+            "[2.000s][info][gc] GC(1) Pause Young 16M->4M(64M) 2.000ms",
+            true,
+        );
+
+        let event = next(&mut events).await;
+        assert_eq!(event.gc_id, 1);
+        assert_eq!(event.collector, "G1");
+        assert_eq!(event.instance, "survival");
+
+        // Replaced by a restarted JVM
+        std::fs::remove_file(&path).unwrap();
+        write("[0.011s][info][gc] Using Parallel", false);
+        write(
+            "[1.000s][info][gc] GC(0) Pause Full 8M->4M(64M) 5.000ms",
+            true,
+        );
+
+        let event = next(&mut events).await;
+        assert_eq!(event.gc_id, 0);
+        assert_eq!(event.collector, "Parallel");
+
+        inotify.shutdown().await;
+    }
+}
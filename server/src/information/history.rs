@@ -0,0 +1,356 @@
+//! Bounded history of past samples, which are downsampled as they age
+
+use std::{collections::VecDeque, time::Duration};
+
+use data::events::{LoadAverage, NetworkUsage, ProcessSnapshot, SystemSnapshot};
+
+use super::{Sample, MIN_INTERVAL};
+
+/// How long every sample is kept for, in seconds
+pub const RAW_RETENTION: u64 = 60 * 60;
+
+/// Upper limit on raw samples, which is an hour of samples at the shortest interval
+const MAX_RAW: usize = (RAW_RETENTION * 1000 / MIN_INTERVAL.as_millis() as u64) as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Every sample
+    Raw,
+    /// The average of each completed minute
+    Minute,
+}
+
+/// Ring buffers of samples, which keep raw samples for [`RAW_RETENTION`] and minute averages for
+/// the configured retention
+#[derive(Debug, Clone)]
+pub struct History {
+    raw: VecDeque<Sample>,
+    minutes: VecDeque<Sample>,
+    /// How long minute averages are kept for, in seconds
+    retention: u64,
+}
+
+impl History {
+    pub fn new(retention: Duration) -> Self {
+        Self {
+            raw: VecDeque::new(),
+            minutes: VecDeque::new(),
+            retention: retention.as_secs(),
+        }
+    }
+
+    /// Add a sample, averaging the previous minute if this is the first sample of a new one
+    ///
+    /// Returns the new minute average, if there was one. Samples from before the latest one,
+    /// which happens when the clock is set back, are dropped.
+    pub fn push(&mut self, sample: Sample) -> Option<&Sample> {
+        let now = sample.system.unixtime;
+        let mut averaged = None;
+
+        if let Some(latest) = self.raw.back() {
+            let minute = minute_of(latest);
+
+            if now < latest.system.unixtime {
+                tracing::debug!("Dropping sample from before the latest in history");
+                return None;
+            }
+
+            if now / 60 != minute {
+                let mut samples = self
+                    .raw
+                    .iter()
+                    .rev()
+                    .take_while(|it| minute_of(it) == minute)
+                    .collect::<Vec<_>>();
+                samples.reverse();
+
+                averaged = Some(average(minute * 60, &samples));
+            }
+        }
+
+        let added_minute = averaged.is_some();
+        self.minutes.extend(averaged);
+        self.raw.push_back(sample);
+        self.expire(now);
+
+        if added_minute {
+            self.minutes.back()
+        } else {
+            None
+        }
+    }
+
+    /// Add a sample at `resolution` as it is, such as one which was saved earlier
+    ///
+    /// Samples from before the latest one at that resolution are dropped.
+    pub fn restore(&mut self, resolution: Resolution, sample: Sample) {
+        let samples = match resolution {
+            Resolution::Raw => &mut self.raw,
+            Resolution::Minute => &mut self.minutes,
+        };
+
+        match samples.back() {
+            Some(latest) if sample.system.unixtime < latest.system.unixtime => {}
+            _ => samples.push_back(sample),
+        }
+    }
+
+    /// Drop everything which is past its retention at `now`
+    pub fn expire(&mut self, now: u64) {
+        while self.raw.len() > MAX_RAW || is_older(self.raw.front(), now, RAW_RETENTION) {
+            self.raw.pop_front();
+        }
+
+        while is_older(self.minutes.front(), now, self.retention) {
+            self.minutes.pop_front();
+        }
+    }
+
+    /// Every sample which is kept, minute averages first, each in the order they were taken
+    pub fn samples(&self) -> impl Iterator<Item = (Resolution, &Sample)> {
+        let minutes = self.minutes.iter().map(|it| (Resolution::Minute, it));
+        let raw = self.raw.iter().map(|it| (Resolution::Raw, it));
+
+        minutes.chain(raw)
+    }
+
+    /// Up to `limit` of the samples taken from `from` to `to` inclusive, at `resolution`, earliest
+    /// first
+    ///
+    /// Without a resolution, raw samples are returned if the range starts within
+    /// [`RAW_RETENTION`] of the latest sample, and minute averages otherwise.
+    pub fn range(
+        &self,
+        from: u64,
+        to: u64,
+        resolution: Option<Resolution>,
+        limit: usize,
+    ) -> (Resolution, Vec<Sample>) {
+        let resolution = resolution.unwrap_or_else(|| match self.raw.back() {
+            Some(latest) if from.saturating_add(RAW_RETENTION) < latest.system.unixtime => {
+                Resolution::Minute
+            }
+            _ => Resolution::Raw,
+        });
+
+        let samples = match resolution {
+            Resolution::Raw => &self.raw,
+            Resolution::Minute => &self.minutes,
+        };
+
+        let start = samples.partition_point(|it| it.system.unixtime < from);
+        let end = samples.partition_point(|it| it.system.unixtime <= to);
+
+        (
+            resolution,
+            samples
+                .range(start..end.max(start))
+                .take(limit)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+fn minute_of(sample: &Sample) -> u64 {
+    sample.system.unixtime / 60
+}
+
+fn is_older(sample: Option<&Sample>, now: u64, retention: u64) -> bool {
+    sample
+        .map(|it| it.system.unixtime + retention < now)
+        .unwrap_or(false)
+}
+
+fn mean(values: impl Iterator<Item = f32>) -> f32 {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), it| (sum + it, count + 1));
+
+    if count == 0 {
+        0.0
+    } else {
+        sum / count as f32
+    }
+}
+
+fn mean_u64(values: impl Iterator<Item = u64>) -> u64 {
+    let (sum, count) = values.fold((0, 0), |(sum, count), it| (sum + it, count + 1));
+
+    sum.checked_div(count).unwrap_or(0)
+}
+
+/// Average of `samples`, which are in the order they were taken, stamped with `unixtime`
+///
+/// Totals, disks, and which processes belong to an instance are taken from the latest sample.
+fn average(unixtime: u64, samples: &[&Sample]) -> Sample {
+    let latest = samples.last().expect("Averaged an empty minute");
+    let systems = samples.iter().map(|it| &it.system).collect::<Vec<_>>();
+
+    let system_f32 = |field: fn(&SystemSnapshot) -> f32| mean(systems.iter().map(|it| field(it)));
+    let system_u64 =
+        |field: fn(&SystemSnapshot) -> u64| mean_u64(systems.iter().map(|it| field(it)));
+
+    let core_usage = (0..latest.system.core_usage.len())
+        .map(|core| {
+            mean(
+                systems
+                    .iter()
+                    .filter_map(|it| it.core_usage.get(core).copied()),
+            )
+        })
+        .collect();
+
+    let load = latest.system.load.as_ref().map(|_| {
+        let loads = systems
+            .iter()
+            .filter_map(|it| it.load.as_ref())
+            .collect::<Vec<_>>();
+
+        LoadAverage {
+            one: mean(loads.iter().map(|it| it.one)),
+            five: mean(loads.iter().map(|it| it.five)),
+            fifteen: mean(loads.iter().map(|it| it.fifteen)),
+        }
+    });
+
+    let networks = latest
+        .system
+        .networks
+        .iter()
+        .map(|network| {
+            let matching = systems
+                .iter()
+                .filter_map(|it| {
+                    it.networks
+                        .iter()
+                        .find(|it| it.interface == network.interface)
+                })
+                .collect::<Vec<_>>();
+
+            NetworkUsage {
+                interface: network.interface.clone(),
+                rx_bytes_per_second: mean(matching.iter().map(|it| it.rx_bytes_per_second)),
+                tx_bytes_per_second: mean(matching.iter().map(|it| it.tx_bytes_per_second)),
+            }
+        })
+        .collect();
+
+    let processes = latest
+        .processes
+        .iter()
+        .map(|process| {
+            let matching = samples
+                .iter()
+                .filter_map(|it| {
+                    it.processes
+                        .iter()
+                        .find(|it| it.instance == process.instance)
+                })
+                .collect::<Vec<_>>();
+
+            let process_u64 =
+                |field: fn(&ProcessSnapshot) -> u64| mean_u64(matching.iter().map(|it| field(it)));
+            let process_f32 =
+                |field: fn(&ProcessSnapshot) -> f32| mean(matching.iter().map(|it| field(it)));
+
+            ProcessSnapshot {
+                unixtime,
+                cpu_usage: process_f32(|it| it.cpu_usage),
+                rss_bytes: process_u64(|it| it.rss_bytes),
+                virtual_bytes: process_u64(|it| it.virtual_bytes),
+                threads: process_u64(|it| it.threads.into()) as u32,
+                open_files: process_u64(|it| it.open_files.into()) as u32,
+                disk_read_bytes_per_second: process_f32(|it| it.disk_read_bytes_per_second),
+                disk_written_bytes_per_second: process_f32(|it| it.disk_written_bytes_per_second),
+                ..process.clone()
+            }
+        })
+        .collect();
+
+    Sample {
+        system: SystemSnapshot {
+            unixtime,
+            cpu_pressure: system_f32(|it| it.cpu_pressure),
+            mem_pressure: system_f32(|it| it.mem_pressure),
+            core_usage,
+            cpu_usage: system_f32(|it| it.cpu_usage),
+            load,
+            mem_used_bytes: system_u64(|it| it.mem_used_bytes),
+            swap_used_bytes: system_u64(|it| it.swap_used_bytes),
+            networks,
+            ..latest.system.clone()
+        },
+        processes,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{History, Resolution, RAW_RETENTION};
+    use crate::information::sample;
+
+    #[test]
+    fn downsamples_minutes() {
+        let mut history = History::new(Duration::from_secs(24 * 60 * 60));
+
+        assert_eq!(history.push(sample(60, 0.25)), None);
+        assert_eq!(history.push(sample(90, 0.75)), None);
+        assert!(history.push(sample(120, 1.0)).is_some());
+
+        let (resolution, minutes) =
+            history.range(0, u64::MAX, Some(Resolution::Minute), usize::MAX);
+        assert_eq!(resolution, Resolution::Minute);
+        assert_eq!(minutes.len(), 1, "Only completed minutes are averaged");
+
+        let minute = &minutes[0];
+        assert_eq!(minute.system.unixtime, 60);
+        assert_eq!(minute.system.cpu_usage, 0.5);
+        assert_eq!(minute.system.core_usage, [50.0]);
+        assert_eq!(minute.processes[0].unixtime, 60);
+        assert_eq!(minute.processes[0].rss_bytes, 50);
+        assert!(minute.processes[0].running);
+    }
+
+    #[test]
+    fn range_and_retention() {
+        let mut history = History::new(Duration::from_secs(24 * 60 * 60));
+        let start = 60 * 20_000;
+
+        for second in (0..2 * RAW_RETENTION).step_by(30) {
+            history.push(sample(start + second, 0.5));
+        }
+
+        let latest = start + 2 * RAW_RETENTION - 30;
+        let (_, raw) = history.range(0, u64::MAX, Some(Resolution::Raw), usize::MAX);
+        assert!(raw.first().unwrap().system.unixtime >= latest - RAW_RETENTION);
+        assert_eq!(raw.last().unwrap().system.unixtime, latest);
+
+        let (resolution, recent) = history.range(latest - 60, latest, None, usize::MAX);
+        assert_eq!(resolution, Resolution::Raw);
+        assert_eq!(recent.len(), 3);
+
+        let (resolution, old) = history.range(start, start + 600, None, usize::MAX);
+        assert_eq!(resolution, Resolution::Minute);
+        assert_eq!(old.len(), 11);
+
+        assert!(history.range(latest, start, None, usize::MAX).1.is_empty());
+        assert!(history
+            .range(u64::MAX, u64::MAX, None, usize::MAX)
+            .1
+            .is_empty());
+
+        let (_, limited) = history.range(0, u64::MAX, Some(Resolution::Raw), 2);
+        assert_eq!(limited, raw[..2], "The earliest are kept");
+
+        // Samples from before the latest are dropped
+        history.push(sample(start, 0.5));
+        assert_eq!(
+            history
+                .range(0, u64::MAX, Some(Resolution::Raw), usize::MAX)
+                .1
+                .last(),
+            raw.last()
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Resource usage of the server processes of managed instances
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use sysinfo::{Pid, Process, ProcessExt, System, SystemExt};
+
+use data::events::ProcessSnapshot;
+
+use crate::application::InstanceConfig;
+
+/// A managed instance, and where to find its server process
+pub(super) struct Instance {
+    id: String,
+    root: PathBuf,
+}
+
+impl Instance {
+    pub(super) fn new(id: &str, config: &InstanceConfig) -> Self {
+        // Working directories are reported fully resolved, so the root has to be as well
+        let root = config
+            .root
+            .canonicalize()
+            .unwrap_or_else(|_| config.root.clone());
+
+        Self {
+            id: id.to_owned(),
+            root,
+        }
+    }
+
+    /// Sum the usage of the server process and all of its children
+    ///
+    /// `elapsed` is the time in seconds since processes were last refreshed
+    pub(super) fn snapshot(
+        &self,
+        system: &System,
+        children: &HashMap<Pid, Vec<Pid>>,
+        unixtime: u64,
+        elapsed: f32,
+    ) -> ProcessSnapshot {
+        let mut snapshot = ProcessSnapshot {
+            unixtime,
+            instance: self.id.clone(),
+            ..Default::default()
+        };
+
+        let server = match self.find_server(system) {
+            Some(server) => server,
+            None => return snapshot,
+        };
+
+        snapshot.running = true;
+        snapshot.uptime_seconds = unixtime.saturating_sub(server.start_time());
+
+        let mut pids = Vec::from([server.pid()]);
+        let mut next = 0;
+        while next < pids.len() {
+            if let Some(found) = children.get(&pids[next]) {
+                pids.extend(found);
+            }
+            next += 1;
+        }
+
+        for process in pids.iter().filter_map(|pid| system.process(*pid)) {
+            let disk = process.disk_usage();
+
+            snapshot.cpu_usage += process.cpu_usage();
+            snapshot.rss_bytes += process.memory() * KB;
+            snapshot.virtual_bytes += process.virtual_memory() * KB;
+            snapshot.threads += count_entries(&proc_path(process.pid(), "task"));
+            snapshot.open_files += count_entries(&proc_path(process.pid(), "fd"));
+
+            if elapsed > 0.0 {
+                snapshot.disk_read_bytes_per_second += disk.read_bytes as f32 / elapsed;
+                snapshot.disk_written_bytes_per_second += disk.written_bytes as f32 / elapsed;
+            }
+        }
+
+        snapshot.pids = pids;
+        snapshot
+    }
+
+    /// The outermost process running from the instance root, and the earliest started if there
+    /// are several
+    fn find_server<'system>(&self, system: &'system System) -> Option<&'system Process> {
+        let in_root = |process: &Process| process.cwd() == self.root;
+
+        system
+            .processes()
+            .values()
+            .filter(|process| in_root(process))
+            .filter(|process| {
+                !process
+                    .parent()
+                    .and_then(|parent| system.process(parent))
+                    .map(in_root)
+                    .unwrap_or(false)
+            })
+            .min_by_key(|process| (process.start_time(), process.pid()))
+    }
+}
+
+/// sysinfo reports process memory in KB
+const KB: u64 = 1024;
+
+/// Map from each process to its direct children
+pub(super) fn children(system: &System) -> HashMap<Pid, Vec<Pid>> {
+    let mut children: HashMap<Pid, Vec<Pid>> = HashMap::new();
+
+    for process in system.processes().values() {
+        if let Some(parent) = process.parent() {
+            children.entry(parent).or_default().push(process.pid());
+        }
+    }
+
+    children
+}
+
+fn proc_path(pid: Pid, entry: &str) -> PathBuf {
+    Path::new("/proc").join(pid.to_string()).join(entry)
+}
+
+/// Entries in a directory, or zero if it can not be read
+fn count_entries(path: &Path) -> u32 {
+    std::fs::read_dir(path)
+        .map(|it| it.count() as u32)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+
+    use sysinfo::{ProcessRefreshKind, RefreshKind, System, SystemExt};
+    use tempdir::TempDir;
+
+    use super::{children, Instance};
+    use crate::application::InstanceConfig;
+
+    #[test]
+    fn finds_server_and_children() {
+        let root = TempDir::new("instance").unwrap();
+        let mut server = Command::new("sh")
+            .args(["-c", "sleep 10 & wait"])
+            .current_dir(root.path())
+            .spawn()
+            .unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(250));
+
+        let system = System::new_with_specifics(
+            RefreshKind::new().with_processes(ProcessRefreshKind::new()),
+        );
+        let instance = Instance::new(
+            "test",
+            &InstanceConfig {
+                root: root.path().into(),
+                gc_log: "gc.log".into(),
+                launch: None,
+            },
+        );
+
+        let snapshot = instance.snapshot(&system, &children(&system), 0, 0.0);
+        server.kill().unwrap();
+        server.wait().unwrap();
+
+        assert!(snapshot.running);
+        assert_eq!(snapshot.instance, "test");
+        assert_eq!(
+            snapshot.pids.len(),
+            2,
+            "Expected sh and sleep: {snapshot:#?}"
+        );
+        assert_eq!(snapshot.pids[0], server.id() as i32);
+        assert!(snapshot.rss_bytes > 0);
+        assert!(snapshot.threads >= 2);
+    }
+}
@@ -0,0 +1,134 @@
+//! Where the metrics in each [`Sample`] come from
+//!
+//! The collector task only knows about a [`Sampler`], which asks each of its sources in turn to
+//! add what they measure to a sample. Host metrics from sysinfo are one source, and anything else,
+//! such as metrics from the servers themselves, can be registered alongside it.
+
+use data::events::SystemSnapshot;
+
+use super::{Collector, Sample};
+use crate::application::Config;
+
+/// Something which adds metrics to each [`Sample`]
+pub trait MetricSource: Send + 'static {
+    /// Add what this source measures to `sample`
+    ///
+    /// `sample.system.unixtime` is already set to the time the sample is for. This is called from
+    /// a blocking thread, so reading files or waiting on the kernel is fine.
+    fn sample(&mut self, sample: &mut Sample);
+}
+
+/// Takes [`Sample`]s from each of its sources, in the order they were registered
+#[derive(Default)]
+pub struct Sampler {
+    sources: Vec<Box<dyn MetricSource>>,
+}
+
+impl Sampler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sampler with host and instance metrics from sysinfo, for the metric groups in `config`
+    pub fn sysinfo(config: &Config) -> Self {
+        Self::new().register(Collector::new(&config.sysinfo.metrics, &config.instances))
+    }
+
+    /// Add a source, which is sampled after all those registered before it
+    pub fn register(mut self, source: impl MetricSource) -> Self {
+        self.sources.push(Box::new(source));
+        self
+    }
+
+    pub fn sample(&mut self) -> Sample {
+        let mut sample = Sample {
+            system: SystemSnapshot {
+                unixtime: super::unixtime(),
+                ..Default::default()
+            },
+            processes: Vec::new(),
+        };
+
+        for source in self.sources.iter_mut() {
+            source.sample(&mut sample);
+        }
+
+        sample
+    }
+}
+
+#[cfg(test)]
+pub use scripted::{sample, Scripted};
+
+#[cfg(test)]
+mod scripted {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    use data::events::{ProcessSnapshot, SystemSnapshot};
+
+    use super::{MetricSource, Sample};
+
+    /// Sample taken at `unixtime`, with the usage of the system and of instance `survival` all
+    /// at `usage`, for tests
+    pub fn sample(unixtime: u64, usage: f32) -> Sample {
+        Sample {
+            system: SystemSnapshot {
+                unixtime,
+                cpu_usage: usage,
+                core_usage: Vec::from([usage * 100.0]),
+                mem_pressure: usage,
+                ..Default::default()
+            },
+            processes: Vec::from([ProcessSnapshot {
+                unixtime,
+                instance: "survival".into(),
+                running: true,
+                rss_bytes: (usage * 100.0) as u64,
+                ..Default::default()
+            }]),
+        }
+    }
+
+    /// Source which replaces each sample with the next one from a script, for tests
+    ///
+    /// Once the script runs out the last sample is repeated. More samples can be added through a
+    /// clone while the collector is running.
+    #[derive(Debug, Clone, Default)]
+    pub struct Scripted {
+        script: Arc<Mutex<VecDeque<Sample>>>,
+        last: Arc<Mutex<Sample>>,
+    }
+
+    impl Scripted {
+        pub fn new(script: impl IntoIterator<Item = Sample>) -> Self {
+            let scripted = Self::default();
+            scripted.extend(script);
+            scripted
+        }
+
+        /// Add samples to the end of the script
+        pub fn extend(&self, samples: impl IntoIterator<Item = Sample>) {
+            self.script.lock().unwrap().extend(samples);
+        }
+
+        /// Samples in the script which have not been taken yet
+        pub fn remaining(&self) -> usize {
+            self.script.lock().unwrap().len()
+        }
+    }
+
+    impl MetricSource for Scripted {
+        fn sample(&mut self, sample: &mut Sample) {
+            let mut last = self.last.lock().unwrap();
+
+            if let Some(next) = self.script.lock().unwrap().pop_front() {
+                *last = next;
+            }
+
+            *sample = last.clone();
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! Append only storage of history on disk, so that it survives restarts
+//!
+//! Every sample is appended to a single log of length delimited records as it is added to the
+//! history. The log is compacted by rewriting it with only what the in-memory history still
+//! keeps, which also drops anything past its retention.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use data::events::{ProcessSnapshot, SystemSnapshot};
+use prost::Message;
+use tracing::{info, warn};
+
+use super::{
+    history::{History, Resolution},
+    Sample,
+};
+use crate::prelude::*;
+
+/// Time between compactions of the log, in seconds
+const COMPACT_INTERVAL: u64 = 60 * 60;
+
+/// A sample as it is written to the log
+#[derive(Clone, PartialEq, Message)]
+struct Record {
+    /// Whether this is a minute average rather than a raw sample
+    #[prost(bool, tag = "1")]
+    minute: bool,
+    #[prost(message, optional, tag = "2")]
+    system: Option<SystemSnapshot>,
+    #[prost(message, repeated, tag = "3")]
+    processes: Vec<ProcessSnapshot>,
+}
+
+pub struct Store {
+    path: PathBuf,
+    file: File,
+    compacted_at: u64,
+}
+
+impl Store {
+    /// Open the log in `dir`, restoring everything in it into `history`, and compact it
+    ///
+    /// A truncated record at the end of the log, left by a crash while writing, is dropped.
+    pub fn open(dir: &Path, history: &mut History, now: u64) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Creating history directory {}", dir.display()))?;
+
+        let path = dir.join("history.log");
+        let mut restored = 0;
+
+        match std::fs::read(&path) {
+            Ok(contents) => {
+                let mut remaining = contents.as_slice();
+
+                while !remaining.is_empty() {
+                    let record = match Record::decode_length_delimited(&mut remaining) {
+                        Ok(record) => record,
+                        Err(e) => {
+                            warn!("Dropping the rest of {}: {e}", path.display());
+                            break;
+                        }
+                    };
+
+                    let resolution = if record.minute {
+                        Resolution::Minute
+                    } else {
+                        Resolution::Raw
+                    };
+
+                    let sample = Sample {
+                        system: record.system.unwrap_or_default(),
+                        processes: record.processes,
+                    };
+
+                    history.restore(resolution, sample);
+                    restored += 1;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Reading history {}", path.display())),
+        }
+
+        history.expire(now);
+        info!("Restored {restored} samples from {}", path.display());
+
+        let mut store = Self {
+            file: open_append(&path)?,
+            path,
+            compacted_at: now,
+        };
+        store.compact(history)?;
+
+        Ok(store)
+    }
+
+    /// Add a sample to the end of the log
+    pub fn append(&mut self, resolution: Resolution, sample: &Sample) -> Result<()> {
+        let buffer = record(resolution, sample).encode_length_delimited_to_vec();
+
+        self.file
+            .write_all(&buffer)
+            .with_context(|| format!("Appending to history {}", self.path.display()))
+    }
+
+    /// Whether it has been long enough since the log was last compacted, in which case it is
+    /// expected to be compacted now
+    pub fn compaction_due(&mut self, now: u64) -> bool {
+        if now < self.compacted_at + COMPACT_INTERVAL {
+            return false;
+        }
+
+        self.compacted_at = now;
+        true
+    }
+
+    /// Replace the log with everything still kept in `history`
+    pub fn compact(&mut self, history: &History) -> Result<()> {
+        let temporary = self.path.with_extension("log.tmp");
+
+        let mut writer = BufWriter::new(
+            File::create(&temporary)
+                .with_context(|| format!("Creating {}", temporary.display()))?,
+        );
+
+        for (resolution, sample) in history.samples() {
+            writer.write_all(&record(resolution, sample).encode_length_delimited_to_vec())?;
+        }
+
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| file.sync_all())
+            .with_context(|| format!("Writing {}", temporary.display()))?;
+
+        std::fs::rename(&temporary, &self.path)
+            .with_context(|| format!("Replacing history {}", self.path.display()))?;
+
+        // The old file was replaced, so appends have to go to the new one
+        self.file = open_append(&self.path)?;
+
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Opening history {}", path.display()))
+}
+
+fn record(resolution: Resolution, sample: &Sample) -> Record {
+    Record {
+        minute: resolution == Resolution::Minute,
+        system: Some(sample.system.clone()),
+        processes: sample.processes.clone(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Write, time::Duration};
+
+    use tempdir::TempDir;
+
+    use super::Store;
+    use crate::information::{
+        history::{History, Resolution},
+        sample,
+    };
+
+    fn history() -> History {
+        History::new(Duration::from_secs(7 * 24 * 60 * 60))
+    }
+
+    #[test]
+    fn restores_after_restart() {
+        let dir = TempDir::new("history").unwrap();
+        let start = 60 * 20_000;
+
+        let mut before = history();
+        let mut store = Store::open(dir.path(), &mut before, start).unwrap();
+
+        for second in (0..300).step_by(10) {
+            let sample = sample(start + second, 0.5);
+            store.append(Resolution::Raw, &sample).unwrap();
+
+            if let Some(minute) = before.push(sample) {
+                store.append(Resolution::Minute, minute).unwrap();
+            }
+        }
+        drop(store);
+
+        // Left behind by a crash part way through a write
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("history.log"))
+            .unwrap()
+            .write_all(&[0x40, 0x01])
+            .unwrap();
+
+        let mut after = history();
+        Store::open(dir.path(), &mut after, start + 300).unwrap();
+
+        assert_eq!(
+            after.samples().collect::<Vec<_>>(),
+            before.samples().collect::<Vec<_>>()
+        );
+
+        // Compacted when opened, and past retention once opened much later
+        let mut later = history();
+        Store::open(dir.path(), &mut later, start + 8 * 24 * 60 * 60).unwrap();
+
+        assert_eq!(later.samples().count(), 0);
+    }
+}
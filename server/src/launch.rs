@@ -0,0 +1,126 @@
+//! Launching the servers of managed instances
+//!
+//! Instances with a [`LaunchConfig`] are started along with the manager, with GC logging added to
+//! their JVM arguments so that their pauses are reported. They are left running when the manager
+//! exits, since stopping a server that way loses whatever it has not saved yet.
+
+use std::process::Stdio;
+
+use tokio::process::Command;
+use tracing::{error, info, warn};
+
+use crate::{
+    application::{Config, InstanceConfig, LaunchConfig},
+    information::gc_log_flag,
+    prelude::*,
+};
+
+/// Start the server of each instance in `config` which should be launched
+pub fn start_instances(config: &Config) -> Result<()> {
+    for (id, instance) in config.instances.iter() {
+        let launch = match &instance.launch {
+            Some(it) => it,
+            None => continue,
+        };
+
+        // The JVM refuses to start when it can not open its GC log
+        if let Some(dir) = instance.root.join(&instance.gc_log).parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Creating the GC log directory of {id}"))?;
+        }
+
+        let mut child = command(instance, launch)
+            .spawn()
+            .with_context(|| format!("Launching {id}"))?;
+
+        info!("Launched Instance {id}");
+
+        let id = id.clone();
+        tokio::task::Builder::new()
+            .name(&format!("Instance {id}"))
+            .spawn(async move {
+                match child.wait().await {
+                    Ok(status) => warn!("Instance {id} Exited With {status}"),
+                    Err(e) => error!("Could Not Wait for Instance {id}: {e}"),
+                }
+            });
+    }
+
+    Ok(())
+}
+
+/// Command which runs the server of `instance`, writing its GC log unless `launch` already asks
+/// for GC logging
+fn command(instance: &InstanceConfig, launch: &LaunchConfig) -> Command {
+    let mut command = Command::new(&launch.java);
+    command.current_dir(&instance.root).args(&launch.jvm_args);
+
+    if launch.jvm_args.iter().any(|it| it.starts_with("-Xlog:gc")) {
+        warn!(
+            "GC logging is already set up in the jvm_args of {}, make sure it writes to {}",
+            instance.root.display(),
+            instance.gc_log.display()
+        );
+    } else {
+        command.arg(gc_log_flag(&instance.gc_log));
+    }
+
+    command
+        .arg("-jar")
+        .arg(&launch.jar)
+        .args(&launch.args)
+        // The console is not used, and the server writes its own logs
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    command
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::command;
+    use crate::application::{InstanceConfig, LaunchConfig};
+
+    fn args(jvm_args: &[&str]) -> Vec<String> {
+        let launch = LaunchConfig {
+            java: "java".into(),
+            jvm_args: jvm_args.iter().map(|it| it.to_string()).collect(),
+            jar: "server.jar".into(),
+            args: Vec::from(["nogui".to_owned()]),
+        };
+        let instance = InstanceConfig {
+            root: PathBuf::from("/srv/minecraft/survival"),
+            gc_log: "logs/gc.log".into(),
+            launch: None,
+        };
+
+        command(&instance, &launch)
+            .as_std()
+            .get_args()
+            .map(|it| it.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn adds_gc_logging() {
+        assert_eq!(
+            args(&["-Xmx4G"]),
+            [
+                "-Xmx4G",
+                "-Xlog:gc*:file=logs/gc.log:time,uptime,level,tags:filecount=5,filesize=10m",
+                "-jar",
+                "server.jar",
+                "nogui"
+            ]
+        );
+
+        assert_eq!(
+            args(&["-Xlog:gc:file=gc.txt"]),
+            ["-Xlog:gc:file=gc.txt", "-jar", "server.jar", "nogui"],
+            "GC logging which was already set up is left alone"
+        );
+    }
+}
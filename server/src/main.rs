@@ -1,3 +1,6 @@
+// Handlers and their helpers return tonic::Status, which is large but not worth boxing
+#![allow(clippy::result_large_err)]
+
 extern crate anyhow;
 extern crate clap;
 extern crate console_subscriber;
@@ -8,16 +11,34 @@ extern crate tracing_subscriber;
 #[macro_use]
 extern crate async_stream;
 
+use std::sync::Arc;
+
+use anyhow::bail;
 use clap::StructOpt;
 
+use application::Config;
+use audit::Audit;
+use auth::Authenticator;
+use bus::EventBus;
 use data::IntoServer;
-use information::SystemInfo;
+use information::{Alerts, Sampler, SystemInfo};
+use subscribers::Subscribers;
+use tonic::service::interceptor::InterceptedService;
 use tracing::info;
 
 mod application;
+mod audit;
+mod auth;
+mod bus;
+mod files;
+mod health;
 mod information;
+mod launch;
 mod prelude;
+mod prometheus;
+mod subscribers;
 mod util;
+mod webhooks;
 
 use prelude::*;
 
@@ -47,13 +68,51 @@ mod hello_world {
 }
 
 mod events {
-    use crate::{information::SystemInfo, prelude::*};
+    use std::{
+        collections::{HashMap, VecDeque},
+        time::Duration,
+    };
+
+    use crate::{
+        audit::{self, Audit},
+        auth::User,
+        bus::{self, EventBus, Filter, Sequenced},
+        information::{self, Alerts, Sample, SystemInfo},
+        prelude::*,
+        subscribers::Subscribers,
+    };
     use data::events::*;
-    use tonic::{Code, Request, Response, Status};
-    use tracing::info;
+    use prost::Message;
+    use tokio::{sync::watch, time::Instant};
+    use tonic::{Request, Response, Status};
+    use tracing::{info, warn};
+
+    /// Longest a subscription may hold events back for a batch
+    const MAX_BATCH: Duration = Duration::from_secs(10);
+
+    /// Longest interval a subscription may rate limit a topic to
+    const MAX_RATE_LIMIT: Duration = Duration::from_secs(10 * 60);
+
+    /// Most samples a History response may hold
+    const MAX_HISTORY_SAMPLES: usize = 1000;
+
+    /// Most encoded bytes of events a History response may hold, well within gRPC's 4MB limit
+    const MAX_HISTORY_BYTES: usize = 3 * 1024 * 1024;
+
+    /// Batches are sent early once they hold this many events, or this many bytes
+    const MAX_BATCH_EVENTS: usize = 1000;
+    const MAX_BATCH_BYTES: usize = 1024 * 1024;
 
     pub struct EventsService {
         pub system_info: SystemInfo,
+        pub alerts: Alerts,
+        pub bus: EventBus,
+        /// Time a stream may go without events before a heartbeat is sent
+        pub heartbeat: Duration,
+        /// Open [`Events::subscribe`] streams
+        pub subscribers: Subscribers,
+        /// Becomes true when the server starts shutting down, which ends every stream
+        pub shutdown: watch::Receiver<bool>,
     }
 
     #[tonic::async_trait]
@@ -65,56 +124,917 @@ mod events {
             request: Request<EventSubscription>,
         ) -> StreamResponse<EventResponse> {
             let system_info = self.system_info.clone();
+            let alerts = self.alerts.clone();
+            let addr = match request.remote_addr() {
+                Some(addr) => addr.to_string(),
+                None => String::from("{unknown}"),
+            };
+            let user = request.extensions().get::<User>().cloned();
+            let heartbeat = self.heartbeat;
+            let mut shutdown = self.shutdown.clone();
+            let bus = self.bus.clone();
+            let filter = Filter::new(request.get_ref());
+            let mut pacer = Pacer::new(request.get_ref());
+
+            let resume_after = request.get_ref().resume_after;
+            let (gap, mut events) = if resume_after == 0 {
+                (None, bus.subscribe())
+            } else {
+                bus.subscribe_after(request.get_ref().resume_epoch, resume_after)
+            };
+            let epoch = bus.epoch();
+            let registration = self
+                .subscribers
+                .register(addr, user, request.get_ref(), &events);
+
+            let stream = stream! {
+                let registration = registration;
+                info!("Event Service Starting Stream {} to {}", registration.id, registration.addr);
+
+                let _sampling = filter.sampling(&system_info);
+
+                if let Some(gap) = gap {
+                    info!("Event Service Stream Could Not Resume After {resume_after}, Replay Starts At {}", gap.next);
+                    registration.sent(1);
+                    yield Ok(response(gap_event(gap), 0));
+                }
 
-            Ok(stream! {
-                match request.remote_addr() {
-                    Some(addr) => info!("Event Service Starting Stream to {addr}"),
-                    None => info!("Event Service Starting Stream to {{unknown}}"),
+                // Alerts raised before subscribing would otherwise never be seen, unless they
+                // are replayed
+                if resume_after == 0 || gap.is_some() {
+                    for raised in alerts.active() {
+                        let event = Event::AlertRaised(raised);
+
+                        if filter.matches(&event) {
+                            registration.sent(1);
+                            yield Ok(response(event, 0));
+                        }
+                    }
                 }
 
-                let mut system_info = system_info.collect();
+                let mut quiet_until = Instant::now() + heartbeat;
 
                 loop {
-                    let info = system_info.next().await;
-                    yield match info {
-                        Some(info) => Ok(EventResponse {
-                            event: Some(Event::SystemSnapshot(info)),
-                        }),
-                        None => Err(Status::new(Code::Ok, "System Information Collector Was Cancelled")),
+                    while let Some(response) = pacer.next(Instant::now()) {
+                        quiet_until = Instant::now() + heartbeat;
+                        registration.sent(match &response.event {
+                            Some(Event::Batch(batch)) => batch.events.len() as u64,
+                            _ => 1,
+                        });
+                        yield Ok(response);
+                    }
+
+                    let wake = pacer.deadline().map_or(quiet_until, |it| it.min(quiet_until));
+
+                    let event = tokio::select! {
+                        event = events.next() => event,
+                        _ = registration.disconnected() => {
+                            info!("Event Service Stream {} Disconnected by an Admin", registration.id);
+                            yield Err(Status::aborted("Disconnected by an admin"));
+                            break;
+                        }
+                        Ok(()) = shutdown.changed() => {
+                            info!("Event Service Stream {} Ended for Shutdown", registration.id);
+                            yield Err(Status::unavailable("The server is shutting down"));
+                            break;
+                        }
+                        _ = tokio::time::sleep_until(wake) => {
+                            if Instant::now() >= quiet_until {
+                                let beat = Event::Heartbeat(Heartbeat {
+                                    unixtime: information::unixtime(),
+                                    latest: bus.latest(),
+                                });
+
+                                quiet_until = Instant::now() + heartbeat;
+                                registration.sent(1);
+                                yield Ok(response(beat, 0));
+                            }
+
+                            continue;
+                        }
+                    };
+
+                    match event {
+                        Some(Ok(Sequenced { sequence, event })) => {
+                            registration.received(sequence);
+
+                            if filter.matches(&event) {
+                                pacer.push(event, sequence, Instant::now());
+                            }
+                        }
+                        Some(Err(gap)) => {
+                            warn!("Event Service Stream {} to {} Skipped {} Events", registration.id, registration.addr, gap.next - gap.after - 1);
+                            registration.received(gap.next - 1);
+                            pacer.push(gap_event(gap), 0, Instant::now());
+                        }
+                        // The bus outlives this service, which holds a handle to it
+                        None => break,
                     }
                 }
-            }
-            .into_msg())
+            };
+
+            // Every event says which run of the server its sequence is from
+            Ok(stream
+                .map(move |it| it.map(|it| EventResponse { epoch, ..it }))
+                .into_msg())
         }
 
         async fn snapshot(
             &self,
             _request: Request<EventSubscription>,
         ) -> Result<Response<EventResponse>, Status> {
-            Ok(EventResponse {
-                event: Some(Event::SystemSnapshot(self.system_info.borrow().clone())),
+            Ok(response(
+                Event::SystemSnapshot(self.system_info.fresh().await.system),
+                0,
+            )
+            .into_msg())
+        }
+
+        async fn set_sampling(
+            &self,
+            request: Request<SamplingSettings>,
+        ) -> Result<Response<SamplingSettings>, Status> {
+            // Sampling is shared by every client, history, and alerts, so only admins may change
+            // it
+            let interval = if request.get_ref().interval_ms == 0 {
+                self.system_info.interval()
+            } else {
+                let user = admin(&request)?;
+                let interval = Duration::from_millis(request.get_ref().interval_ms.into());
+
+                info!("{} Set the Sampling Interval to {interval:?}", user.name);
+                self.system_info.set_interval(interval)
+            };
+
+            Ok(SamplingSettings {
+                interval_ms: interval.as_millis().try_into().unwrap_or(u32::MAX),
             }
             .into_msg())
         }
+
+        async fn history(
+            &self,
+            request: Request<HistoryRequest>,
+        ) -> Result<Response<HistoryResponse>, Status> {
+            let request = request.into_inner();
+            let to = if request.to == 0 {
+                u64::MAX
+            } else {
+                request.to
+            };
+
+            if request.from > to {
+                return Err(Status::invalid_argument("from must not be after to"));
+            }
+
+            let resolution = match request.resolution() {
+                Resolution::Auto => None,
+                Resolution::Raw => Some(information::Resolution::Raw),
+                Resolution::Minute => Some(information::Resolution::Minute),
+            };
+
+            // One more than is sent, to tell whether any were left out
+            let (resolution, samples) = self
+                .system_info
+                .history(request.from, to, resolution, MAX_HISTORY_SAMPLES + 1)
+                .ok_or_else(|| Status::failed_precondition("History is disabled on this server"))?;
+
+            let mut response = HistoryResponse {
+                truncated: samples.len() > MAX_HISTORY_SAMPLES,
+                ..Default::default()
+            };
+            let mut bytes = 0;
+
+            for sample in samples.into_iter().take(MAX_HISTORY_SAMPLES) {
+                let sample = events(sample).collect::<Vec<_>>();
+                let size = sample.iter().map(|it| it.encoded_len()).sum::<usize>();
+
+                if bytes + size > MAX_HISTORY_BYTES {
+                    response.truncated = true;
+                    break;
+                }
+
+                bytes += size;
+                response.events.extend(sample);
+            }
+
+            response.set_resolution(match resolution {
+                information::Resolution::Raw => Resolution::Raw,
+                information::Resolution::Minute => Resolution::Minute,
+            });
+
+            Ok(response.into_msg())
+        }
+    }
+
+    pub struct EventsAdminService {
+        pub bus: EventBus,
+        pub subscribers: Subscribers,
+        /// None when the audit log is disabled
+        pub audit: Option<Audit>,
+    }
+
+    #[tonic::async_trait]
+    impl EventsAdmin for EventsAdminService {
+        async fn list_subscribers(
+            &self,
+            request: Request<ListSubscribersRequest>,
+        ) -> Result<Response<ListSubscribersResponse>, Status> {
+            admin(&request)?;
+
+            let subscribers = self
+                .subscribers
+                .list()
+                .iter()
+                .map(|it| it.to_message(&self.bus))
+                .collect();
+
+            Ok(ListSubscribersResponse { subscribers }.into_msg())
+        }
+
+        async fn disconnect(
+            &self,
+            request: Request<DisconnectRequest>,
+        ) -> Result<Response<DisconnectResponse>, Status> {
+            let user = admin(&request)?;
+            let id = request.get_ref().id;
+
+            if !self.subscribers.disconnect(id) {
+                return Err(Status::not_found(format!("No subscriber {id}")));
+            }
+
+            info!("{} Disconnected Event Service Stream {id}", user.name);
+
+            Ok(DisconnectResponse {}.into_msg())
+        }
+
+        async fn query_audit(
+            &self,
+            request: Request<AuditQuery>,
+        ) -> Result<Response<AuditResponse>, Status> {
+            admin(&request)?;
+
+            let audit = self.audit.as_ref().ok_or_else(|| {
+                Status::failed_precondition("The audit log is disabled on this server")
+            })?;
+
+            let request = request.into_inner();
+            let to = if request.to == 0 {
+                u64::MAX
+            } else {
+                request.to
+            };
+
+            if request.from > to {
+                return Err(Status::invalid_argument("from must not be after to"));
+            }
+
+            let filter = Filter::new(&EventSubscription {
+                topics: request.topics,
+                instances: request.instances,
+                min_severity: request.min_severity,
+                ..Default::default()
+            });
+
+            let query = audit::Query {
+                from: request.from,
+                to,
+                filter,
+                limit: match request.limit {
+                    0 => audit::DEFAULT_LIMIT,
+                    limit => (limit as usize).min(audit::MAX_LIMIT),
+                },
+            };
+
+            let (entries, truncated) = audit
+                .query(query)
+                .await
+                .map_err(|e| Status::internal(format!("Could not search the audit log: {e:#}")))?;
+
+            let entries = entries
+                .into_iter()
+                .map(|it| AuditEntry {
+                    unixtime: it.unixtime,
+                    event: Some(EventResponse {
+                        epoch: it.epoch,
+                        ..response(it.event, it.sequence)
+                    }),
+                })
+                .collect();
+
+            Ok(AuditResponse { entries, truncated }.into_msg())
+        }
+    }
+
+    /// The user making a request, if they are an admin
+    fn admin<T>(request: &Request<T>) -> Result<&User, Status> {
+        let user = request
+            .extensions()
+            .get::<User>()
+            .ok_or_else(|| Status::unauthenticated("Missing bearer token"))?;
+
+        user.require_admin()?;
+
+        Ok(user)
+    }
+
+    /// Holds events back to keep to a subscription's rate limits and batching
+    struct Pacer {
+        intervals: HashMap<Topic, Duration>,
+        /// Each topic and instance which has sent a rate limited event
+        limited: HashMap<(Topic, String), Limited>,
+        /// Zero when not batching
+        batch: Duration,
+        /// Events ready to be sent, oldest first
+        ready: VecDeque<EventResponse>,
+        /// Encoded size of the ready events
+        ready_bytes: usize,
+        /// When the ready events have to be sent by
+        flush_at: Instant,
+    }
+
+    struct Limited {
+        /// When the next event may be sent
+        next: Instant,
+        /// The latest event which arrived too soon
+        waiting: Option<EventResponse>,
+    }
+
+    impl Pacer {
+        fn new(subscription: &EventSubscription) -> Self {
+            let intervals = subscription
+                .rate_limits
+                .iter()
+                .filter(|it| !matches!(it.topic(), Topic::Unknown | Topic::Alert))
+                .map(|it| {
+                    let interval = Duration::from_millis(it.interval_ms.into());
+                    (it.topic(), interval.min(MAX_RATE_LIMIT))
+                })
+                .collect();
+
+            Self {
+                intervals,
+                limited: HashMap::new(),
+                batch: Duration::from_millis(subscription.batch_ms.into()).min(MAX_BATCH),
+                ready: VecDeque::new(),
+                ready_bytes: 0,
+                flush_at: Instant::now(),
+            }
+        }
+
+        /// Send an event once its rate limit and batching allow
+        fn push(&mut self, event: Event, sequence: u64, now: Instant) {
+            let limit = Filter::describe(&event).and_then(|(topic, _, instance)| {
+                let interval = *self.intervals.get(&topic)?;
+                Some(((topic, instance.unwrap_or_default().to_owned()), interval))
+            });
+
+            if let Some((key, interval)) = limit {
+                let limited = self.limited.entry(key).or_insert(Limited {
+                    next: now,
+                    waiting: None,
+                });
+
+                if limited.next > now {
+                    limited.waiting = Some(response(event, sequence));
+                    return;
+                }
+
+                limited.next = now + interval;
+            }
+
+            self.enqueue(response(event, sequence), now);
+        }
+
+        fn enqueue(&mut self, event: EventResponse, now: Instant) {
+            if self.ready.is_empty() {
+                self.flush_at = now + self.batch;
+            }
+
+            self.ready_bytes += event.encoded_len();
+            self.ready.push_back(event);
+        }
+
+        /// When something which is held back has to be sent
+        fn deadline(&self) -> Option<Instant> {
+            let waiting = self
+                .limited
+                .values()
+                .filter(|it| it.waiting.is_some())
+                .map(|it| it.next);
+            let flush = (!self.ready.is_empty()).then_some(self.flush_at);
+
+            waiting.chain(flush).min()
+        }
+
+        /// The next event to send by `now`, if any
+        fn next(&mut self, now: Instant) -> Option<EventResponse> {
+            let mut released = Vec::new();
+
+            for ((topic, _), limited) in self.limited.iter_mut() {
+                if limited.next <= now {
+                    if let Some(event) = limited.waiting.take() {
+                        limited.next = now + self.intervals[topic];
+                        released.push(event);
+                    }
+                }
+            }
+
+            released.sort_by_key(|it| it.sequence);
+            for event in released {
+                self.enqueue(event, now);
+            }
+
+            if self.batch.is_zero() {
+                let event = self.ready.pop_front()?;
+                self.ready_bytes -= event.encoded_len();
+                return Some(event);
+            }
+
+            let full = self.ready.len() >= MAX_BATCH_EVENTS || self.ready_bytes >= MAX_BATCH_BYTES;
+
+            if self.ready.is_empty() || (self.flush_at > now && !full) {
+                return None;
+            }
+
+            self.ready_bytes = 0;
+            let events = Vec::from(std::mem::take(&mut self.ready));
+            let sequence = events.iter().map(|it| it.sequence).max().unwrap_or(0);
+
+            Some(response(Event::Batch(Batch { events }), sequence))
+        }
+    }
+
+    /// The system snapshot of a sample, followed by each of its process snapshots
+    fn events(sample: Sample) -> impl Iterator<Item = EventResponse> {
+        std::iter::once(Event::SystemSnapshot(sample.system))
+            .chain(sample.processes.into_iter().map(Event::ProcessSnapshot))
+            .map(|event| response(event, 0))
+    }
+
+    fn response(event: Event, sequence: u64) -> EventResponse {
+        EventResponse {
+            event: Some(event),
+            sequence,
+            ..Default::default()
+        }
+    }
+
+    fn gap_event(gap: bus::Gap) -> Event {
+        Event::Gap(Gap {
+            after: gap.after,
+            next: gap.next,
+        })
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::time::Duration;
+
+        use data::events::*;
+        use tokio::sync::watch;
+        use tonic::{Code, Request, Status};
+
+        use super::{EventsService, Pacer, MAX_BATCH, MAX_BATCH_EVENTS};
+        use crate::{
+            application::{AlertMetric, AlertRule, Config},
+            auth::User,
+            bus::EventBus,
+            information::{self, sample, Sampler, Scripted},
+            prelude::*,
+            subscribers::Subscribers,
+        };
+
+        /// Config which does not keep history, so samples are only taken while subscribed
+        fn config() -> Config {
+            let mut config = Config::default();
+            config.sysinfo.history.enabled = false;
+            config
+        }
+
+        /// Events service sampling from `sampler`, along with the sender which shuts it down
+        fn service(
+            config: &Config,
+            sampler: Sampler,
+            bus: &EventBus,
+        ) -> (EventsService, watch::Sender<bool>) {
+            let system_info = information::start_sysinfo(config, sampler);
+            let alerts = information::start_alerts(config, &system_info, bus).unwrap();
+            let (shutdown_tx, shutdown) = watch::channel(false);
+
+            let service = EventsService {
+                system_info,
+                alerts,
+                bus: bus.clone(),
+                heartbeat: Duration::from_secs(15),
+                subscribers: Subscribers::default(),
+                shutdown,
+            };
+
+            (service, shutdown_tx)
+        }
+
+        #[test]
+        fn rate_limits_and_batches() {
+            let system = |unixtime| {
+                Event::SystemSnapshot(SystemSnapshot {
+                    unixtime,
+                    ..Default::default()
+                })
+            };
+            let gc = |gc_id| {
+                Event::GcEvent(GcEvent {
+                    gc_id,
+                    ..Default::default()
+                })
+            };
+            let start = tokio::time::Instant::now();
+            let at = |ms| start + Duration::from_millis(ms);
+
+            let mut subscription = EventSubscription::default();
+            subscription.rate_limits.push(RateLimit {
+                topic: Topic::System.into(),
+                interval_ms: 10_000,
+            });
+
+            let mut pacer = Pacer::new(&subscription);
+            pacer.push(system(1), 1, at(0));
+            assert_eq!(pacer.next(at(0)).unwrap().sequence, 1);
+
+            // Later snapshots replace the one waiting, other topics are not held back
+            pacer.push(system(2), 2, at(3_000));
+            pacer.push(gc(0), 3, at(4_000));
+            pacer.push(system(3), 4, at(6_000));
+            assert_eq!(pacer.next(at(6_000)).unwrap().sequence, 3);
+            assert_eq!(pacer.next(at(6_000)), None);
+            assert_eq!(pacer.deadline(), Some(at(10_000)));
+            assert_eq!(pacer.next(at(10_000)).unwrap().sequence, 4);
+            assert_eq!(pacer.deadline(), None);
+
+            subscription.batch_ms = 200;
+            let mut pacer = Pacer::new(&subscription);
+            pacer.push(gc(1), 5, at(0));
+            pacer.push(gc(2), 6, at(50));
+            assert_eq!(pacer.next(at(50)), None);
+            assert_eq!(pacer.deadline(), Some(at(200)));
+
+            let batch = pacer.next(at(200)).unwrap();
+            assert_eq!(batch.sequence, 6, "The latest event in the batch");
+            match batch.event {
+                Some(Event::Batch(Batch { events })) => {
+                    let sequences = events.iter().map(|it| it.sequence).collect::<Vec<_>>();
+                    assert_eq!(sequences, [5, 6]);
+                }
+                other => panic!("Expected a batch, got {other:?}"),
+            }
+            assert_eq!(pacer.deadline(), None);
+
+            // Long batches are cut short, and full ones are sent right away
+            subscription.batch_ms = u32::MAX;
+            let mut pacer = Pacer::new(&subscription);
+            pacer.push(gc(1), 1, at(0));
+            assert_eq!(pacer.deadline(), Some(at(0) + MAX_BATCH));
+
+            for sequence in 2..=MAX_BATCH_EVENTS as u64 {
+                pacer.push(gc(1), sequence, at(0));
+            }
+            let batch = pacer.next(at(0)).unwrap();
+            assert_eq!(batch.sequence, MAX_BATCH_EVENTS as u64);
+            assert_eq!(pacer.deadline(), None);
+        }
+
+        #[tokio::test]
+        async fn resumes_after_sequence() {
+            let bus = EventBus::new(8, 2);
+            let (service, _shutdown) = service(&config(), Sampler::new(), &bus);
+
+            let pauses = bus.publisher::<GcEvent>();
+            for gc_id in 0..3 {
+                pauses.publish(GcEvent {
+                    gc_id,
+                    ..Default::default()
+                });
+            }
+
+            let resume_from = |resume_epoch, resume_after| {
+                let subscription = EventSubscription {
+                    resume_after,
+                    resume_epoch,
+                    topics: Vec::from([Topic::Gc.into()]),
+                    ..Default::default()
+                };
+
+                service.subscribe(Request::new(subscription))
+            };
+            let resume = |resume_after| resume_from(bus.epoch(), resume_after);
+
+            let events = resume(1)
+                .await
+                .unwrap()
+                .into_inner()
+                .map(|it| it.unwrap())
+                .take(2)
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(events[0].sequence, 2);
+            assert_eq!(events[1].sequence, 3);
+            assert_eq!(events[1].epoch, bus.epoch());
+
+            // Only the latest two are kept
+            pauses.publish(GcEvent::default());
+
+            let events = resume(1)
+                .await
+                .unwrap()
+                .into_inner()
+                .map(|it| it.unwrap())
+                .take(3)
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(events[0].event, Some(Event::Gap(Gap { after: 1, next: 3 })));
+            assert_eq!(events[1].sequence, 3);
+            assert_eq!(events[2].sequence, 4);
+
+            // Sequences from another run of the server are not comparable, even when lower
+            let events = resume_from(bus.epoch() + 1, 3)
+                .await
+                .unwrap()
+                .into_inner()
+                .map(|it| it.unwrap())
+                .take(2)
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(events[0].event, Some(Event::Gap(Gap { after: 3, next: 3 })));
+            assert_eq!(events[1].sequence, 3);
+
+            // Nothing is replayed without resuming
+            let mut events = resume(0).await.unwrap().into_inner();
+            pauses.publish(GcEvent::default());
+            assert_eq!(events.next().await.unwrap().unwrap().sequence, 5);
+        }
+
+        #[tokio::test]
+        async fn only_admins_set_sampling() {
+            let config = config();
+            let (service, _shutdown) = service(&config, Sampler::new(), &EventBus::default());
+
+            let set = |interval_ms, admin: Option<bool>| {
+                let mut request = Request::new(SamplingSettings { interval_ms });
+
+                if let Some(admin) = admin {
+                    request.extensions_mut().insert(User {
+                        name: "josiah".into(),
+                        admin,
+                    });
+                }
+
+                service.set_sampling(request)
+            };
+
+            let code = |result: Result<_, Status>| result.unwrap_err().code();
+            assert_eq!(code(set(1, None).await), Code::Unauthenticated);
+            assert_eq!(code(set(1, Some(false)).await), Code::PermissionDenied);
+
+            let current = set(0, None).await.unwrap().into_inner();
+            assert_eq!(
+                current.interval_ms,
+                config.sysinfo.interval.as_millis() as u32
+            );
+
+            let applied = set(1, Some(true)).await.unwrap().into_inner();
+            assert_eq!(
+                applied.interval_ms,
+                information::MIN_INTERVAL.as_millis() as u32,
+                "Clamped to the shortest interval"
+            );
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn heartbeats_and_cleanup() {
+            let bus = EventBus::default();
+            let (service, _shutdown) = service(&config(), Sampler::new(), &bus);
+
+            let subscription = EventSubscription {
+                topics: Vec::from([Topic::Gc.into()]),
+                ..Default::default()
+            };
+            let mut events = service
+                .subscribe(Request::new(subscription))
+                .await
+                .unwrap()
+                .into_inner();
+
+            // Filtered out, so the stream stays quiet
+            bus.publisher::<AlertCleared>()
+                .publish(AlertCleared::default());
+
+            let started = tokio::time::Instant::now();
+            let beat = events.next().await.unwrap().unwrap();
+            assert_eq!(started.elapsed(), Duration::from_secs(15));
+            assert!(matches!(
+                beat.event,
+                Some(Event::Heartbeat(Heartbeat { latest: 1, .. }))
+            ));
+            assert_eq!(beat.sequence, 0);
+
+            assert_eq!(service.subscribers.len(), 1);
+            drop(events);
+            assert_eq!(service.subscribers.len(), 0);
+
+            // Disconnected by an admin
+            let mut events = service
+                .subscribe(Request::new(EventSubscription::default()))
+                .await
+                .unwrap()
+                .into_inner();
+            let id = service.subscribers.list()[0].id;
+            assert!(service.subscribers.disconnect(id));
+            assert_eq!(
+                events.next().await.unwrap().unwrap_err().code(),
+                tonic::Code::Aborted
+            );
+            assert!(events.next().await.is_none());
+            assert_eq!(service.subscribers.len(), 0);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn shutdown_ends_streams() {
+            let (service, shutdown_tx) = service(&config(), Sampler::new(), &EventBus::default());
+
+            let subscription = EventSubscription {
+                topics: Vec::from([Topic::Gc.into()]),
+                ..Default::default()
+            };
+            let mut events = service
+                .subscribe(Request::new(subscription))
+                .await
+                .unwrap()
+                .into_inner();
+
+            shutdown_tx.send(true).unwrap();
+
+            let started = tokio::time::Instant::now();
+            assert_eq!(
+                events.next().await.unwrap().unwrap_err().code(),
+                tonic::Code::Unavailable
+            );
+            assert!(
+                started.elapsed() < Duration::from_secs(15),
+                "Before any heartbeat"
+            );
+            assert!(events.next().await.is_none());
+            assert_eq!(service.subscribers.len(), 0);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn subscribe_streams_samples_and_alerts() {
+            let mut config = Config::default();
+            config.sysinfo.interval = Duration::from_secs(10);
+            config.sysinfo.history.persist = false;
+            config.alerts.push(AlertRule {
+                name: "memory".into(),
+                metric: AlertMetric::MemoryUsage,
+                above: Some(0.9),
+                below: None,
+                clear: None,
+                duration: Duration::ZERO,
+                cooldown: Duration::ZERO,
+            });
+
+            let script = Scripted::new([sample(600, 0.5), sample(610, 0.95)]);
+            let bus = EventBus::default();
+            let (service, _shutdown) = service(&config, Sampler::new().register(script), &bus);
+            information::publish_samples(&service.system_info, &bus);
+
+            let events = service
+                .subscribe(Request::new(EventSubscription::default()))
+                .await
+                .unwrap()
+                .into_inner()
+                .map(|it| it.unwrap().event.unwrap())
+                // Paused time skips ahead to the next heartbeat while samples are taken on the
+                // blocking pool
+                .filter(|it| futures::future::ready(!matches!(it, Event::Heartbeat(_))))
+                .take(5)
+                .collect::<Vec<_>>()
+                .await;
+
+            assert_eq!(service.subscribers.len(), 0);
+
+            assert!(matches!(&events[0], Event::SystemSnapshot(it) if it.unixtime == 600));
+            assert!(matches!(&events[1], Event::ProcessSnapshot(it) if it.instance == "survival"));
+
+            let raised = events.iter().find_map(|it| match it {
+                Event::AlertRaised(it) => Some(it),
+                _ => None,
+            });
+            assert_eq!(raised.unwrap().unixtime, 610);
+            assert!(events
+                .iter()
+                .any(|it| matches!(it, Event::SystemSnapshot(it) if it.unixtime == 610)));
+        }
     }
 }
 
-#[tracing::instrument(skip(sysinfo))]
-async fn launch_services(sysinfo: SystemInfo) -> Result<()> {
+#[tracing::instrument(skip_all)]
+async fn launch_services(
+    config: Arc<Config>,
+    sysinfo: SystemInfo,
+    alerts: Alerts,
+    bus: EventBus,
+    audit: Option<Audit>,
+    inotify: async_inotify::handle::Handle,
+) -> Result<()> {
     tracing::info!("Starting gRPC Server at 0.0.0.0:50051");
 
-    tonic::transport::Server::builder()
+    let authenticator = Authenticator::new(&config.auth);
+    let subscribers = Subscribers::default();
+
+    let exporter = config.prometheus.listen.map(|addr| {
+        let exporter = prometheus::Exporter {
+            sysinfo: sysinfo.clone(),
+            alerts: alerts.clone(),
+            inotify: inotify.clone(),
+            bus: bus.clone(),
+            event_subscribers: subscribers.clone(),
+        };
+
+        prometheus::serve(addr, exporter)
+    });
+
+    // Keepalives notice clients which went away without closing their streams
+    let heartbeat = config.events.heartbeat;
+    if heartbeat.is_zero() {
+        bail!("events.heartbeat must be longer than zero");
+    }
+
+    // Streams never end on their own, so they are told to before the server waits for them
+    let (shutdown_tx, shutdown) = tokio::sync::watch::channel(false);
+    let shutdown_signal = async move {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Could not listen for ctrl-c: {e}");
+            return std::future::pending().await;
+        }
+
+        info!("Shutting Down gRPC Server");
+        let _ = shutdown_tx.send(true);
+    };
+
+    let health = health::start_health(&config, &sysinfo, &inotify);
+
+    // Describes the services to tools such as grpcurl, which still authenticate to call them
+    let reflection = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(data::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(
+            tonic_health::proto::GRPC_HEALTH_V1_FILE_DESCRIPTOR_SET,
+        )
+        .build()
+        .context("Building the Reflection Service")?;
+
+    let grpc = tonic::transport::Server::builder()
         .concurrency_limit_per_connection(32)
-        .add_service(
+        .tcp_keepalive(Some(heartbeat))
+        .http2_keepalive_interval(Some(heartbeat))
+        .add_service(InterceptedService::new(
             events::EventsService {
                 system_info: sysinfo.clone(),
+                alerts,
+                bus: bus.clone(),
+                heartbeat,
+                subscribers: subscribers.clone(),
+                shutdown,
             }
             .into_server(),
-        )
+            authenticator.optional_interceptor(),
+        ))
+        .add_service(InterceptedService::new(
+            events::EventsAdminService {
+                bus,
+                subscribers,
+                audit,
+            }
+            .into_server(),
+            authenticator.interceptor(),
+        ))
+        .add_service(health)
+        .add_service(reflection)
         .add_service(hello_world::HelloWorldImpl { sysinfo }.into_server())
-        .serve("0.0.0.0:50051".parse()?)
-        .await
-        .context("Running Tonic Unauthenticated Server")
+        .add_service(InterceptedService::new(
+            files::FilesService { config, inotify }.into_server(),
+            authenticator.interceptor(),
+        ))
+        .serve_with_shutdown("0.0.0.0:50051".parse()?, shutdown_signal);
+
+    let grpc = async { grpc.await.context("Running Tonic Unauthenticated Server") };
+
+    // The exporter only ends on an error, so it does not hold up shutting down
+    match exporter {
+        Some(exporter) => tokio::select! {
+            res = grpc => res,
+            res = exporter => res,
+        },
+        None => grpc.await,
+    }
 }
 
 #[tokio::main]
@@ -125,13 +1045,34 @@ async fn main() -> Result<()> {
 
     tracing::info!("{args:#?}");
 
-    let rx = information::start_sysinfo();
+    let config = Arc::new(Config::load(&args.config_location_or_default())?);
+
+    tracing::info!("{config:#?}");
+
+    let bus = EventBus::default();
+    let inotify = async_inotify::new().context("Starting inotify watcher")?;
+    launch::start_instances(&config).context("Launching instances")?;
+    let gc = information::start_gc(&config, &inotify, &bus);
+    let rx = information::start_sysinfo(&config, Sampler::sysinfo(&config).register(gc.source()));
+    information::publish_samples(&rx, &bus);
+    let alerts = information::start_alerts(&config, &rx, &bus).context("Starting alert rules")?;
+    webhooks::start_webhooks(&config, &rx, &bus).context("Starting webhooks")?;
+    let audit = audit::start_audit(&config, &rx, &bus).context("Starting the audit log")?;
 
     tokio::task::Builder::new()
         .name("gRPC Server")
-        .spawn(launch_services(rx.clone()))
+        .spawn(launch_services(
+            config,
+            rx.clone(),
+            alerts,
+            bus,
+            audit,
+            inotify.clone(),
+        ))
         .await??;
 
+    inotify.shutdown().await;
+
     info!("Ended with value: {:#?}", rx.borrow());
 
     Ok(())
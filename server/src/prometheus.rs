@@ -0,0 +1,580 @@
+//! Prometheus text format exporter for system information and the manager's own state
+
+use std::{convert::Infallible, fmt::Write, net::SocketAddr};
+
+use async_inotify::handle::Handle;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use tracing::info;
+
+use crate::{
+    bus::EventBus,
+    information::{Alerts, Sample, SystemInfo},
+    prelude::*,
+    subscribers::Subscribers,
+};
+
+/// Every metric name starts with this
+const PREFIX: &str = "mcmanager";
+
+/// Everything the exporter reports on
+#[derive(Clone)]
+pub struct Exporter {
+    pub sysinfo: SystemInfo,
+    pub alerts: Alerts,
+    pub inotify: Handle,
+    pub bus: EventBus,
+    /// Open Events.Subscribe streams
+    pub event_subscribers: Subscribers,
+}
+
+/// Serve `GET /metrics` on `addr` until an error occurs
+#[tracing::instrument(skip(exporter))]
+pub async fn serve(addr: SocketAddr, exporter: Exporter) -> Result<()> {
+    info!("Starting Prometheus Exporter at {addr}");
+
+    let make_service = make_service_fn(move |_| {
+        let exporter = exporter.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let exporter = exporter.clone();
+
+                async move { Ok::<_, Infallible>(exporter.respond(request).await) }
+            }))
+        }
+    });
+
+    Server::try_bind(&addr)
+        .with_context(|| format!("Binding Prometheus Exporter to {addr}"))?
+        .serve(make_service)
+        .await
+        .context("Running Prometheus Exporter")
+}
+
+impl Exporter {
+    async fn respond(&self, request: Request<Body>) -> Response<Body> {
+        if request.method() != Method::GET || request.uri().path() != "/metrics" {
+            let mut response = Response::new(Body::from("Not Found\n"));
+            *response.status_mut() = StatusCode::NOT_FOUND;
+
+            return response;
+        }
+
+        let mut response = Response::new(Body::from(self.render().await));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            "text/plain; version=0.0.4".parse().expect("Valid header"),
+        );
+
+        response
+    }
+
+    async fn render(&self) -> String {
+        let sample = self.sysinfo.fresh().await;
+        let mut out = Writer::default();
+
+        if sample.system.unixtime != 0 {
+            system(&mut out, &sample);
+            processes(&mut out, &sample);
+            pressure(&mut out, &sample);
+            cgroups(&mut out, &sample);
+            gc(&mut out, &sample);
+        }
+
+        self.internal(&mut out);
+
+        out.0
+    }
+
+    fn internal(&self, out: &mut Writer) {
+        let inotify = self.inotify.stats();
+        let alerts = self.alerts.active();
+
+        out.family(
+            "event_subscribers",
+            "gauge",
+            "Open Events.Subscribe streams",
+        );
+        out.sample("event_subscribers", &[], self.event_subscribers.len());
+
+        out.family(
+            "events_published_total",
+            "counter",
+            "Events published on the internal event bus",
+        );
+        out.sample("events_published_total", &[], self.bus.latest());
+
+        out.family(
+            "sysinfo_subscriptions",
+            "gauge",
+            "Subscriptions keeping the collector sampling, including history and alerts",
+        );
+        out.sample("sysinfo_subscriptions", &[], self.sysinfo.subscriptions());
+
+        out.family(
+            "sysinfo_samples_total",
+            "counter",
+            "Samples taken since the manager started",
+        );
+        out.sample("sysinfo_samples_total", &[], self.sysinfo.samples());
+
+        out.family("alert_active", "gauge", "Alerts which are currently raised");
+        for alert in &alerts {
+            out.sample(
+                "alert_active",
+                &[("rule", &alert.rule), ("subject", &alert.subject)],
+                1,
+            );
+        }
+
+        out.family(
+            "inotify_watches",
+            "gauge",
+            "Kernel inotify watch descriptors held",
+        );
+        out.sample("inotify_watches", &[], inotify.watches);
+
+        out.family(
+            "inotify_watchers",
+            "gauge",
+            "File watches being served, several of which may share a kernel watch",
+        );
+        out.sample("inotify_watchers", &[], inotify.watchers);
+
+        out.family(
+            "inotify_events_total",
+            "counter",
+            "Events read from inotify since the manager started",
+        );
+        out.sample("inotify_events_total", &[], inotify.events);
+    }
+}
+
+fn system(out: &mut Writer, sample: &Sample) {
+    let system = &sample.system;
+
+    out.family(
+        "sample_timestamp_seconds",
+        "gauge",
+        "Unix time the reported sample was taken at",
+    );
+    out.sample("sample_timestamp_seconds", &[], system.unixtime);
+
+    out.family(
+        "cpu_usage_ratio",
+        "gauge",
+        "Usage of all cores together, from 0 to 1",
+    );
+    out.sample("cpu_usage_ratio", &[], system.cpu_usage);
+
+    out.family(
+        "core_usage_percent",
+        "gauge",
+        "Usage of each core, from 0 to 100",
+    );
+    for (core, usage) in system.core_usage.iter().enumerate() {
+        out.sample("core_usage_percent", &[("core", &core.to_string())], *usage);
+    }
+
+    if let Some(load) = &system.load {
+        out.family("load_average", "gauge", "System load average");
+        out.sample("load_average", &[("period", "1m")], load.one);
+        out.sample("load_average", &[("period", "5m")], load.five);
+        out.sample("load_average", &[("period", "15m")], load.fifteen);
+    }
+
+    for (name, help, value) in [
+        ("memory_used_bytes", "Memory in use", system.mem_used_bytes),
+        ("memory_total_bytes", "Total memory", system.mem_total_bytes),
+        ("swap_used_bytes", "Swap in use", system.swap_used_bytes),
+        ("swap_total_bytes", "Total swap", system.swap_total_bytes),
+    ] {
+        out.family(name, "gauge", help);
+        out.sample(name, &[], value);
+    }
+
+    out.family("disk_total_bytes", "gauge", "Size of each mounted disk");
+    for disk in &system.disks {
+        out.sample("disk_total_bytes", &disk_labels(disk), disk.total_bytes);
+    }
+
+    out.family(
+        "disk_available_bytes",
+        "gauge",
+        "Space left on each mounted disk",
+    );
+    for disk in &system.disks {
+        out.sample(
+            "disk_available_bytes",
+            &disk_labels(disk),
+            disk.available_bytes,
+        );
+    }
+
+    out.family(
+        "network_receive_bytes_per_second",
+        "gauge",
+        "Receive rate of each network interface",
+    );
+    for network in &system.networks {
+        out.sample(
+            "network_receive_bytes_per_second",
+            &[("interface", &network.interface)],
+            network.rx_bytes_per_second,
+        );
+    }
+
+    out.family(
+        "network_transmit_bytes_per_second",
+        "gauge",
+        "Transmit rate of each network interface",
+    );
+    for network in &system.networks {
+        out.sample(
+            "network_transmit_bytes_per_second",
+            &[("interface", &network.interface)],
+            network.tx_bytes_per_second,
+        );
+    }
+}
+
+fn disk_labels(disk: &data::events::DiskUsage) -> [(&str, &str); 3] {
+    [
+        ("device", &disk.name),
+        ("mount_point", &disk.mount_point),
+        ("file_system", &disk.file_system),
+    ]
+}
+
+fn processes(out: &mut Writer, sample: &Sample) {
+    if sample.processes.is_empty() {
+        return;
+    }
+
+    out.family(
+        "instance_up",
+        "gauge",
+        "Whether the instance's server process is running",
+    );
+    for process in &sample.processes {
+        out.sample(
+            "instance_up",
+            &[("instance", &process.instance)],
+            u8::from(process.running),
+        );
+    }
+
+    let running = || sample.processes.iter().filter(|it| it.running);
+
+    macro_rules! instance_gauge {
+        ($name:literal, $help:literal, $field:ident) => {
+            out.family($name, "gauge", $help);
+            for process in running() {
+                out.sample($name, &[("instance", &process.instance)], process.$field);
+            }
+        };
+    }
+
+    instance_gauge!(
+        "instance_cpu_usage_percent",
+        "CPU usage of the server and its children, 100 for each fully used core",
+        cpu_usage
+    );
+    instance_gauge!(
+        "instance_resident_memory_bytes",
+        "Resident memory of the server and its children",
+        rss_bytes
+    );
+    instance_gauge!(
+        "instance_virtual_memory_bytes",
+        "Virtual memory of the server and its children",
+        virtual_bytes
+    );
+    instance_gauge!(
+        "instance_threads",
+        "Threads of the server and its children",
+        threads
+    );
+    instance_gauge!(
+        "instance_open_files",
+        "Open file descriptors of the server and its children",
+        open_files
+    );
+    instance_gauge!(
+        "instance_disk_read_bytes_per_second",
+        "Disk read rate of the server and its children",
+        disk_read_bytes_per_second
+    );
+    instance_gauge!(
+        "instance_disk_written_bytes_per_second",
+        "Disk write rate of the server and its children",
+        disk_written_bytes_per_second
+    );
+    instance_gauge!(
+        "instance_uptime_seconds",
+        "Time since the server process started",
+        uptime_seconds
+    );
+}
+
+fn pressure(out: &mut Writer, sample: &Sample) {
+    let pressure = match sample.system.pressure {
+        Some(ref pressure) => pressure,
+        None => return,
+    };
+
+    out.family(
+        "pressure_percent",
+        "gauge",
+        "Share of time tasks were stalled waiting on a resource, system wide",
+    );
+
+    for (resource, it) in [
+        ("cpu", &pressure.cpu),
+        ("memory", &pressure.memory),
+        ("io", &pressure.io),
+    ] {
+        let it = match it {
+            Some(it) => it,
+            None => continue,
+        };
+
+        for (kind, window, value) in [
+            ("some", "10s", it.some_avg10),
+            ("some", "60s", it.some_avg60),
+            ("some", "300s", it.some_avg300),
+            ("full", "10s", it.full_avg10),
+            ("full", "60s", it.full_avg60),
+            ("full", "300s", it.full_avg300),
+        ] {
+            out.sample(
+                "pressure_percent",
+                &[("resource", resource), ("kind", kind), ("window", window)],
+                value,
+            );
+        }
+    }
+}
+
+fn cgroups(out: &mut Writer, sample: &Sample) {
+    // The manager's own cgroup has no instance
+    let cgroups = sample
+        .system
+        .cgroup
+        .iter()
+        .map(|it| ("", it))
+        .chain(sample.processes.iter().filter_map(|process| {
+            let cgroup = process.cgroup.as_ref()?;
+            Some((process.instance.as_str(), cgroup))
+        }))
+        .collect::<Vec<_>>();
+
+    if cgroups.is_empty() {
+        return;
+    }
+
+    macro_rules! cgroup_metric {
+        ($name:literal, $kind:literal, $help:literal, |$it:ident| $value:expr) => {
+            out.family($name, $kind, $help);
+            for (instance, $it) in &cgroups {
+                out.sample(
+                    $name,
+                    &[("cgroup", &$it.path), ("instance", instance)],
+                    $value,
+                );
+            }
+        };
+    }
+
+    cgroup_metric!(
+        "cgroup_memory_current_bytes",
+        "gauge",
+        "Memory charged to the cgroup",
+        |it| it.memory_current_bytes
+    );
+    cgroup_metric!(
+        "cgroup_memory_max_bytes",
+        "gauge",
+        "Lowest memory limit of the cgroup and its parents, 0 when unlimited",
+        |it| it.memory_max_bytes
+    );
+    cgroup_metric!(
+        "cgroup_cpu_limit_cores",
+        "gauge",
+        "Cores the cgroup may use, 0 when unlimited",
+        |it| it.cpu_limit
+    );
+    cgroup_metric!(
+        "cgroup_cpu_usage_cores",
+        "gauge",
+        "Cores used by the cgroup",
+        |it| it.cpu_usage
+    );
+    cgroup_metric!(
+        "cgroup_cpu_throttled_seconds_total",
+        "counter",
+        "Time the cgroup was throttled by its CPU limit",
+        |it| it.cpu_throttled_us as f64 / 1e6
+    );
+}
+
+fn gc(out: &mut Writer, sample: &Sample) {
+    let stats = sample
+        .processes
+        .iter()
+        .filter_map(|process| Some((process.instance.as_str(), process.gc.as_ref()?)))
+        .collect::<Vec<_>>();
+
+    if stats.is_empty() {
+        return;
+    }
+
+    macro_rules! gc_gauge {
+        ($name:literal, $help:literal, |$it:ident| $value:expr) => {
+            out.family($name, "gauge", $help);
+            for (instance, $it) in &stats {
+                out.sample(
+                    $name,
+                    &[("instance", instance), ("collector", &$it.collector)],
+                    $value,
+                );
+            }
+        };
+    }
+
+    gc_gauge!(
+        "instance_gc_pauses",
+        "Garbage collection pauses over the last few minutes",
+        |it| it.pauses
+    );
+    gc_gauge!(
+        "instance_gc_pause_max_seconds",
+        "Longest garbage collection pause over the last few minutes",
+        |it| it.pause_max_ms / 1000.0
+    );
+    gc_gauge!(
+        "instance_gc_paused_ratio",
+        "Share of the last few minutes spent in garbage collection pauses",
+        |it| it.paused
+    );
+    gc_gauge!(
+        "instance_gc_heap_after_bytes",
+        "Heap in use after the latest garbage collection",
+        |it| it.heap_after_bytes
+    );
+    gc_gauge!(
+        "instance_gc_heap_total_bytes",
+        "Heap committed at the latest garbage collection",
+        |it| it.heap_total_bytes
+    );
+}
+
+/// Builds up a response in the text exposition format
+#[derive(Default)]
+struct Writer(String);
+
+impl Writer {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {PREFIX}_{name} {help}");
+        let _ = writeln!(self.0, "# TYPE {PREFIX}_{name} {kind}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Value) {
+        let _ = write!(self.0, "{PREFIX}_{name}");
+
+        if !labels.is_empty() {
+            self.0.push('{');
+
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.0.push(',');
+                }
+
+                let _ = write!(self.0, "{label}=\"{}\"", escape(value));
+            }
+
+            self.0.push('}');
+        }
+
+        self.0.push(' ');
+        value.write(&mut self.0);
+        self.0.push('\n');
+    }
+}
+
+/// A sample value, as spelled in the text exposition format
+trait Value {
+    fn write(self, out: &mut String);
+}
+
+macro_rules! integer_value {
+    ($($ty:ty),*) => {$(
+        impl Value for $ty {
+            fn write(self, out: &mut String) {
+                let _ = write!(out, "{self}");
+            }
+        }
+    )*};
+}
+
+integer_value!(i32, u8, u32, u64, usize);
+
+macro_rules! float_value {
+    ($($ty:ty),*) => {$(
+        impl Value for $ty {
+            // Rust spells these `NaN`, `inf` and `-inf`, which scrapers do not accept
+            fn write(self, out: &mut String) {
+                if self.is_nan() {
+                    out.push_str("NaN");
+                } else if self == <$ty>::INFINITY {
+                    out.push_str("+Inf");
+                } else if self == <$ty>::NEG_INFINITY {
+                    out.push_str("-Inf");
+                } else {
+                    let _ = write!(out, "{self}");
+                }
+            }
+        }
+    )*};
+}
+
+float_value!(f32, f64);
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::Writer;
+
+    #[test]
+    fn text_format() {
+        let mut out = Writer::default();
+
+        out.family("instance_up", "gauge", "Whether it is up");
+        out.sample("instance_up", &[("instance", "a \"quoted\"\\name")], 1);
+        out.sample("instance_up", &[], 0.5);
+        out.sample("load", &[], f32::NAN);
+        out.sample("load", &[], f32::INFINITY);
+        out.sample("load", &[], f64::NEG_INFINITY);
+
+        assert_eq!(
+            out.0,
+            "# HELP mcmanager_instance_up Whether it is up\n\
+             # TYPE mcmanager_instance_up gauge\n\
+             mcmanager_instance_up{instance=\"a \\\"quoted\\\"\\\\name\"} 1\n\
+             mcmanager_instance_up 0.5\n\
+             mcmanager_load NaN\n\
+             mcmanager_load +Inf\n\
+             mcmanager_load -Inf\n"
+        );
+    }
+}
@@ -0,0 +1,254 @@
+//! Registry of the open Events.Subscribe streams
+//!
+//! Each stream holds a [`Registration`] for as long as it is alive, which it keeps up to date with
+//! what it has sent, and which an admin can use to disconnect it.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use data::events::{EventSubscription, Subscriber, Topic};
+use tokio::sync::Notify;
+use tracing::info;
+
+use crate::{
+    auth::User,
+    bus::{BusSubscription, EventBus},
+    information,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct Subscribers {
+    inner: Arc<Mutex<Registry>>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    /// Id of the latest subscription
+    latest: u64,
+    active: BTreeMap<u64, Arc<Subscription>>,
+}
+
+/// One open stream
+#[derive(Debug)]
+pub struct Subscription {
+    pub id: u64,
+    pub addr: String,
+    pub user: Option<User>,
+    pub topics: Vec<Topic>,
+    pub instances: Vec<String>,
+    pub started_unixtime: u64,
+    sent: AtomicU64,
+    /// Sequence of the latest bus event the stream received, whether or not it was sent
+    received: AtomicU64,
+    disconnect: Notify,
+}
+
+impl Subscribers {
+    /// Add a stream to the registry, until the returned registration is dropped
+    ///
+    /// `events` is only used for where the stream starts out, so that it is not counted as lagging
+    /// behind events published before it subscribed, and a resumed stream is counted as lagging
+    /// behind the events it has yet to replay.
+    pub fn register(
+        &self,
+        addr: String,
+        user: Option<User>,
+        subscription: &EventSubscription,
+        events: &BusSubscription,
+    ) -> Registration {
+        let mut registry = self.inner.lock().unwrap();
+        registry.latest += 1;
+
+        let subscription = Arc::new(Subscription {
+            id: registry.latest,
+            addr,
+            user,
+            topics: subscription
+                .topics()
+                .filter(|it| *it != Topic::Unknown)
+                .collect(),
+            instances: subscription.instances.clone(),
+            started_unixtime: information::unixtime(),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(events.start()),
+            disconnect: Notify::new(),
+        });
+
+        registry
+            .active
+            .insert(subscription.id, subscription.clone());
+
+        Registration {
+            subscribers: self.clone(),
+            subscription,
+        }
+    }
+
+    /// Every open stream, oldest first
+    pub fn list(&self) -> Vec<Arc<Subscription>> {
+        self.inner
+            .lock()
+            .unwrap()
+            .active
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().active.len()
+    }
+
+    /// End a stream, returning false if it has already ended
+    pub fn disconnect(&self, id: u64) -> bool {
+        match self.inner.lock().unwrap().active.get(&id) {
+            Some(subscription) => {
+                // Stores a permit, so the stream ends even if it is not waiting right now
+                subscription.disconnect.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Subscription {
+    /// Count events sent to the client
+    pub fn sent(&self, events: u64) {
+        self.sent.fetch_add(events, Ordering::Relaxed);
+    }
+
+    /// Note the sequence of a bus event which the stream has received
+    pub fn received(&self, sequence: u64) {
+        self.received.fetch_max(sequence, Ordering::Relaxed);
+    }
+
+    /// Wait until an admin disconnects the stream
+    pub async fn disconnected(&self) {
+        self.disconnect.notified().await
+    }
+
+    pub fn to_message(&self, bus: &EventBus) -> Subscriber {
+        let mut subscriber = Subscriber {
+            id: self.id,
+            remote_addr: self.addr.clone(),
+            user: self
+                .user
+                .as_ref()
+                .map(|it| it.name.clone())
+                .unwrap_or_default(),
+            instances: self.instances.clone(),
+            started_unixtime: self.started_unixtime,
+            events_sent: self.sent.load(Ordering::Relaxed),
+            lag: bus
+                .latest()
+                .saturating_sub(self.received.load(Ordering::Relaxed)),
+            ..Default::default()
+        };
+
+        for topic in &self.topics {
+            subscriber.push_topics(*topic);
+        }
+
+        subscriber
+    }
+}
+
+/// Keeps a stream in the registry for as long as it is alive
+#[derive(Debug)]
+pub struct Registration {
+    subscribers: Subscribers,
+    subscription: Arc<Subscription>,
+}
+
+impl std::ops::Deref for Registration {
+    type Target = Subscription;
+
+    fn deref(&self) -> &Self::Target {
+        &self.subscription
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.subscribers
+            .inner
+            .lock()
+            .unwrap()
+            .active
+            .remove(&self.subscription.id);
+
+        info!(
+            "Event Service Stream {} to {} Ended",
+            self.subscription.id, self.subscription.addr
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use data::events::{EventSubscription, SystemSnapshot, Topic};
+
+    use super::Subscribers;
+    use crate::{auth::User, bus::EventBus};
+
+    #[test]
+    fn registers_and_disconnects() {
+        let bus = EventBus::default();
+        let systems = bus.publisher::<SystemSnapshot>();
+        systems.publish(SystemSnapshot::default());
+
+        let subscribers = Subscribers::default();
+        let user = User {
+            name: "admin".into(),
+            admin: true,
+        };
+        let mut request = EventSubscription::default();
+        request.push_topics(Topic::Gc);
+
+        let first = subscribers.register("first".into(), Some(user), &request, &bus.subscribe());
+        let second = subscribers.register("second".into(), None, &request, &bus.subscribe());
+        assert_eq!(subscribers.len(), 2);
+
+        systems.publish(SystemSnapshot::default());
+        systems.publish(SystemSnapshot::default());
+        first.received(2);
+        first.sent(1);
+
+        let listed = subscribers
+            .list()
+            .iter()
+            .map(|it| it.to_message(&bus))
+            .collect::<Vec<_>>();
+        assert_eq!(listed[0].remote_addr, "first");
+        assert_eq!(listed[0].user, "admin");
+        assert_eq!(listed[0].topics().collect::<Vec<_>>(), [Topic::Gc]);
+        assert_eq!((listed[0].events_sent, listed[0].lag), (1, 1));
+        assert_eq!(listed[1].user, "");
+        assert_eq!(listed[1].lag, 2, "Only events after subscribing");
+
+        // Resumed streams still have the events since where they resumed to catch up on
+        let (_, events) = bus.subscribe_after(bus.epoch(), 1);
+        let resumed = subscribers.register("resumed".into(), None, &request, &events);
+        assert_eq!(
+            subscribers.list()[2].to_message(&bus).lag,
+            2,
+            "Replayed events are still to be received"
+        );
+        drop(resumed);
+
+        assert!(subscribers.disconnect(second.id));
+        let id = second.id;
+        drop(second);
+        assert!(!subscribers.disconnect(id));
+        assert_eq!(subscribers.len(), 1);
+
+        drop(first);
+        assert_eq!(subscribers.len(), 0);
+    }
+}
@@ -0,0 +1,372 @@
+//! Webhooks which POST events from the bus to a URL as JSON
+//!
+//! Each hook has its own task and bus subscription, and delivers events one at a time in the order
+//! they were published. A hook which is down holds up only its own events, until it falls far
+//! enough behind that the bus drops some of them.
+
+use std::time::Duration;
+
+use anyhow::bail;
+use data::events::{Event, EventResponse};
+use hmac::{Hmac, Mac};
+use hyper::{
+    client::HttpConnector,
+    header::{CONTENT_TYPE, USER_AGENT},
+    Body, Client, Method, Request, StatusCode, Uri,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use sha2::Sha256;
+use tracing::{error, info, warn};
+
+use crate::{
+    application::{Config, WebhookConfig},
+    bus::{BusSubscription, EventBus, Filter, Sequenced},
+    information::{self, SystemInfo},
+    prelude::*,
+};
+
+/// Give up on an attempt which has not been answered by now
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Retries are never further apart than this
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Start a task for each webhook in `config`
+pub fn start_webhooks(config: &Config, info: &SystemInfo, bus: &EventBus) -> Result<()> {
+    let hooks = config
+        .events
+        .webhooks
+        .iter()
+        .map(Webhook::new)
+        .collect::<Result<Vec<_>>>()?;
+
+    for hook in hooks {
+        let sampling = hook.filter.sampling(info);
+        let events = bus.subscribe();
+        let name = format!("Webhook {}", hook.name);
+
+        tokio::task::Builder::new().name(&name).spawn(async move {
+            let _sampling = sampling;
+            hook.run(events).await
+        });
+    }
+
+    Ok(())
+}
+
+struct Webhook {
+    name: String,
+    uri: Uri,
+    secret: Option<Hmac<Sha256>>,
+    filter: Filter,
+    retries: u32,
+    backoff: Duration,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+/// How an attempt to deliver an event went
+enum Attempt {
+    Delivered,
+    /// Worth trying again, such as when the server is down or overloaded
+    Failed(String),
+    /// Will fail the same way if tried again
+    Rejected(StatusCode),
+}
+
+impl Webhook {
+    fn new(config: &WebhookConfig) -> Result<Self> {
+        let uri = config
+            .url
+            .parse::<Uri>()
+            .with_context(|| format!("Parsing the url of webhook {}", config.name))?;
+
+        if !matches!(uri.scheme_str(), Some("http" | "https")) {
+            bail!("The url of webhook {} must be http or https", config.name);
+        }
+
+        let secret = config
+            .secret
+            .as_ref()
+            .map(|it| Hmac::new_from_slice(it.as_bytes()).expect("HMAC takes keys of any length"));
+
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        Ok(Self {
+            name: config.name.clone(),
+            uri,
+            secret,
+            filter: Filter::from_config(&config.topics, &config.instances, config.min_severity),
+            retries: config.retries,
+            backoff: config.backoff,
+            client: Client::builder().build(connector),
+        })
+    }
+
+    async fn run(self, mut events: BusSubscription) {
+        info!("Starting Webhook {}", self.name);
+        let epoch = events.epoch();
+
+        while let Some(event) = events.next().await {
+            match event {
+                Ok(Sequenced { sequence, event }) if self.filter.matches(&event) => {
+                    self.deliver(event, sequence, epoch).await;
+                }
+                Ok(_) => {}
+                Err(gap) => warn!(
+                    "Webhook {} Skipped {} Events While Retrying",
+                    self.name,
+                    gap.next - gap.after - 1
+                ),
+            }
+        }
+    }
+
+    /// Deliver an event, retrying with backoff until it is delivered or out of retries
+    async fn deliver(&self, event: Event, sequence: u64, epoch: u64) {
+        let kind = kind(&event);
+        let body = serde_json::to_vec(&EventResponse {
+            event: Some(event),
+            sequence,
+            epoch,
+        })
+        .expect("Events serialize to JSON");
+
+        let mut backoff = self.backoff;
+
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+
+            match self.attempt(&body, kind, sequence).await {
+                Attempt::Delivered => return,
+                Attempt::Failed(reason) => warn!(
+                    "Webhook {} Could Not Deliver Event {sequence} ({}/{}): {reason}",
+                    self.name,
+                    attempt + 1,
+                    self.retries + 1
+                ),
+                Attempt::Rejected(status) => {
+                    error!(
+                        "Webhook {} Rejected Event {sequence} With {status}, Dropping It",
+                        self.name
+                    );
+                    return;
+                }
+            }
+        }
+
+        error!(
+            "Webhook {} Ran Out of Retries for Event {sequence}, Dropping It",
+            self.name
+        );
+    }
+
+    async fn attempt(&self, body: &[u8], kind: &str, sequence: u64) -> Attempt {
+        // Each attempt is stamped afresh, so that receivers can refuse old deliveries
+        let timestamp = information::unixtime();
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .header(USER_AGENT, concat!("mcmanager/", env!("CARGO_PKG_VERSION")))
+            .header("x-mcmanager-event", kind)
+            .header("x-mcmanager-sequence", sequence)
+            .header("x-mcmanager-timestamp", timestamp);
+
+        if let Some(secret) = &self.secret {
+            request = request.header(
+                "x-mcmanager-signature",
+                sign(secret.clone(), timestamp, body),
+            );
+        }
+
+        let request = request
+            .body(Body::from(body.to_vec()))
+            .expect("Valid webhook request");
+
+        let response = match tokio::time::timeout(TIMEOUT, self.client.request(request)).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => return Attempt::Failed(e.to_string()),
+            Err(_) => return Attempt::Failed(format!("No response after {TIMEOUT:?}")),
+        };
+
+        let status = response.status();
+
+        if status.is_success() {
+            Attempt::Delivered
+        } else if status.is_server_error()
+            || status == StatusCode::TOO_MANY_REQUESTS
+            || status == StatusCode::REQUEST_TIMEOUT
+        {
+            Attempt::Failed(format!("Responded with {status}"))
+        } else {
+            Attempt::Rejected(status)
+        }
+    }
+}
+
+/// The `x-mcmanager-signature` of a body, which covers `{timestamp}.{body}` so that a captured
+/// delivery can not be replayed later with a new timestamp
+fn sign(mut secret: Hmac<Sha256>, timestamp: u64, body: &[u8]) -> String {
+    secret.update(format!("{timestamp}.").as_bytes());
+    secret.update(body);
+    format!("sha256={}", hex::encode(secret.finalize().into_bytes()))
+}
+
+/// Name of the kind of event, as in its JSON
+fn kind(event: &Event) -> &'static str {
+    match event {
+        Event::SystemSnapshot(_) => "system_snapshot",
+        Event::ProcessSnapshot(_) => "process_snapshot",
+        Event::AlertRaised(_) => "alert_raised",
+        Event::AlertCleared(_) => "alert_cleared",
+        Event::GcEvent(_) => "gc_event",
+        Event::Gap(_) => "gap",
+        Event::Heartbeat(_) => "heartbeat",
+        Event::Batch(_) => "batch",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use data::events::{AlertCleared, AlertRaised};
+    use hmac::{Hmac, Mac};
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server, StatusCode,
+    };
+    use sha2::Sha256;
+    use tokio::sync::mpsc;
+
+    use super::Webhook;
+    use crate::{
+        application::{EventSeverity, EventTopic, WebhookConfig},
+        bus::EventBus,
+        information,
+    };
+
+    /// Stand-in for a webhook receiver, which answers with each status in turn and then with 200
+    fn receiver(
+        statuses: Vec<StatusCode>,
+    ) -> (SocketAddr, mpsc::UnboundedReceiver<Request<Vec<u8>>>) {
+        let statuses = Arc::new(Mutex::new(statuses.into_iter()));
+        let (sender, received) = mpsc::unbounded_channel();
+
+        let make_service = make_service_fn(move |_| {
+            let statuses = statuses.clone();
+            let sender = sender.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let status = statuses.lock().unwrap().next().unwrap_or(StatusCode::OK);
+                    let sender = sender.clone();
+
+                    async move {
+                        let (parts, body) = request.into_parts();
+                        let body = hyper::body::to_bytes(body).await.unwrap().to_vec();
+                        sender.send(Request::from_parts(parts, body)).unwrap();
+
+                        let mut response = Response::new(Body::empty());
+                        *response.status_mut() = status;
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        (addr, received)
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_and_retries() {
+        let (addr, mut received) = receiver(Vec::from([StatusCode::SERVICE_UNAVAILABLE]));
+
+        let config = WebhookConfig {
+            name: "chat".into(),
+            url: format!("http://{addr}/hook"),
+            secret: Some("hunter2".into()),
+            topics: Vec::from([EventTopic::Alert]),
+            instances: Vec::new(),
+            min_severity: EventSeverity::Warning,
+            retries: 2,
+            backoff: Duration::from_millis(10),
+        };
+
+        let bus = EventBus::default();
+        let hook = Webhook::new(&config).unwrap();
+        tokio::spawn(hook.run(bus.subscribe()));
+
+        // Filtered out, since clearing is only info
+        bus.publisher::<AlertCleared>()
+            .publish(AlertCleared::default());
+        bus.publisher::<AlertRaised>().publish(AlertRaised {
+            rule: "memory".into(),
+            value: 0.95,
+            ..Default::default()
+        });
+
+        let first = received.recv().await.unwrap();
+        let retry = received.recv().await.unwrap();
+        assert_eq!(first.body(), retry.body(), "The same event, retried");
+
+        assert_eq!(retry.uri().path(), "/hook");
+        assert_eq!(retry.headers()["x-mcmanager-event"], "alert_raised");
+        assert_eq!(retry.headers()["x-mcmanager-sequence"], "2");
+
+        let timestamp = retry.headers()["x-mcmanager-timestamp"].to_str().unwrap();
+        let age = information::unixtime() - timestamp.parse::<u64>().unwrap();
+        assert!(age < 60, "Stamped when sent");
+
+        let mut secret = Hmac::<Sha256>::new_from_slice(b"hunter2").unwrap();
+        secret.update(format!("{timestamp}.").as_bytes());
+        secret.update(retry.body());
+        let expected = format!("sha256={}", hex::encode(secret.finalize().into_bytes()));
+        assert_eq!(retry.headers()["x-mcmanager-signature"], expected.as_str());
+
+        let body: serde_json::Value = serde_json::from_slice(retry.body()).unwrap();
+        assert_eq!(body["sequence"], 2);
+        assert_eq!(body["event"]["alert_raised"]["rule"], "memory");
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), received.recv())
+                .await
+                .is_err(),
+            "Nothing else is sent once delivered"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_urls() {
+        let mut config = WebhookConfig {
+            name: "chat".into(),
+            url: "ftp://example.com".into(),
+            secret: None,
+            topics: Vec::new(),
+            instances: Vec::new(),
+            min_severity: EventSeverity::Info,
+            retries: 0,
+            backoff: Duration::ZERO,
+        };
+        assert!(Webhook::new(&config).is_err());
+
+        config.url = "https://example.com/hooks/mcmanager".into();
+        assert!(Webhook::new(&config).is_ok());
+    }
+}